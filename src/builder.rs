@@ -0,0 +1,178 @@
+use crate::callbacks::Passthrough;
+use crate::event::TerminalEvent;
+use crate::render::Renderer;
+use crate::virtual_terminal::{AltScreen, StatusPosition, VirtualTerminal};
+use portable_pty::CommandBuilder;
+use std::ffi::OsStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Terminal type advertised to commands by default. vt100 understands the
+/// xterm control sequences with 256 colors that programs emit for it.
+pub const DEFAULT_TERM: &str = "xterm-256color";
+
+/// Options for spawning a command in a [`VirtualTerminal`].
+pub struct VirtualTerminalBuilder {
+    pub(crate) command: CommandBuilder,
+    pub(crate) rows: u16,
+    pub(crate) cols: u16,
+    pub(crate) min_height: u16,
+    pub(crate) max_height: Option<u16>,
+    pub(crate) scrollback: usize,
+    pub(crate) print_finished_lines: bool,
+    pub(crate) status_position: StatusPosition,
+    pub(crate) alt_screen: AltScreen,
+    pub(crate) passthrough: Vec<Passthrough>,
+    pub(crate) renderer: Option<Box<dyn Renderer>>,
+    pub(crate) subscribers: Vec<Sender<TerminalEvent>>,
+}
+
+impl VirtualTerminalBuilder {
+    /// Starts a command that inherits the environment, with `TERM` set to
+    /// [`DEFAULT_TERM`].
+    pub fn new(program: impl AsRef<OsStr>) -> Self {
+        Self::from_command(CommandBuilder::new(program)).term(DEFAULT_TERM)
+    }
+
+    /// Starts from a fully configured portable-pty command, leaving its
+    /// environment untouched.
+    pub fn from_command(command: CommandBuilder) -> Self {
+        VirtualTerminalBuilder {
+            command,
+            rows: 24,
+            cols: 80,
+            min_height: 0,
+            max_height: None,
+            scrollback: 10_000,
+            print_finished_lines: false,
+            status_position: StatusPosition::Off,
+            alt_screen: AltScreen::Fixed,
+            passthrough: Vec::new(),
+            renderer: None,
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.command.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command.args(args);
+        self
+    }
+
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Self {
+        self.command.env(key, value);
+        self
+    }
+
+    pub fn env_remove(mut self, key: impl AsRef<OsStr>) -> Self {
+        self.command.env_remove(key);
+        self
+    }
+
+    pub fn env_clear(mut self) -> Self {
+        self.command.env_clear();
+        self
+    }
+
+    /// Sets `TERM`, the terminal type the command sees.
+    pub fn term(self, name: impl AsRef<OsStr>) -> Self {
+        self.env("TERM", name)
+    }
+
+    pub fn cwd(mut self, dir: impl AsRef<OsStr>) -> Self {
+        self.command.cwd(dir);
+        self
+    }
+
+    /// Size of the virtual terminal.
+    pub fn size(mut self, rows: u16, cols: u16) -> Self {
+        self.rows = rows;
+        self.cols = cols;
+        self
+    }
+
+    /// Draws the panel exactly `rows` high, showing the last rows of output
+    /// up to the cursor. Short for the same [`min_height`] and
+    /// [`max_height`].
+    ///
+    /// [`min_height`]: Self::min_height
+    /// [`max_height`]: Self::max_height
+    pub fn height(self, rows: u16) -> Self {
+        self.min_height(rows).max_height(rows)
+    }
+
+    /// Keeps the panel at least `rows` high, padded with blank rows, so it
+    /// doesn't shrink whenever the output does.
+    pub fn min_height(mut self, rows: u16) -> Self {
+        self.min_height = rows;
+        self
+    }
+
+    /// Draws at most `rows` rows of the virtual terminal, the last ones up to
+    /// the cursor. Without a limit the panel shows every row in use.
+    pub fn max_height(mut self, rows: u16) -> Self {
+        self.max_height = Some(rows);
+        self
+    }
+
+    /// Lines of history kept above the visible screen.
+    pub fn scrollback(mut self, lines: usize) -> Self {
+        self.scrollback = lines;
+        self
+    }
+
+    /// Prints lines once they scroll off the top of the virtual terminal
+    /// into the real terminal's scrollback above the panel, so they are kept
+    /// like the output of a command run without `detach`. Rows above a
+    /// panel cut down by the height limits are printed as well. The lines
+    /// are captured as they scroll, so the scrollback is kept at two lines
+    /// or more.
+    pub fn print_finished_lines(mut self, enabled: bool) -> Self {
+        self.print_finished_lines = enabled;
+        self
+    }
+
+    /// Where to draw the status line, if at all.
+    pub fn status(mut self, position: StatusPosition) -> Self {
+        self.status_position = position;
+        self
+    }
+
+    /// How to show programs that switch to the alternate screen.
+    pub fn alt_screen(mut self, policy: AltScreen) -> Self {
+        self.alt_screen = policy;
+        self
+    }
+
+    /// Lets the command send `kind` of sequences through to the real
+    /// terminal.
+    pub fn passthrough(mut self, kind: Passthrough) -> Self {
+        self.passthrough.push(kind);
+        self
+    }
+
+    /// Replaces the default [`InlineRenderer`](crate::InlineRenderer).
+    pub fn renderer(mut self, renderer: impl Renderer + 'static) -> Self {
+        self.renderer = Some(Box::new(renderer));
+        self
+    }
+
+    /// Returns a receiver for events of the terminal once it is spawned.
+    /// Subscribing before spawning guarantees no output is missed.
+    pub fn subscribe(&mut self) -> Receiver<TerminalEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn spawn(self) -> anyhow::Result<VirtualTerminal> {
+        VirtualTerminal::spawn(self)
+    }
+}
@@ -0,0 +1,317 @@
+use crate::render::{CursorShape, Hyperlink};
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::ops::Range;
+use vt100::Screen;
+
+/// Hyperlinks remembered for redrawing. Older ones have long scrolled out of
+/// view in practice.
+const MAX_HYPERLINKS: usize = 1000;
+
+/// Sequences meant for the real terminal that the child may pass through to
+/// it.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Passthrough {
+    /// Writes to the system clipboard with OSC 52
+    Clipboard,
+    /// The audible bell
+    Bell,
+    /// Desktop notifications with OSC 9 or OSC 777
+    Notifications,
+}
+
+/// Terminal state that vt100 does not track itself, collected from the
+/// sequences it hands back to the parser callbacks.
+#[derive(Default)]
+pub(crate) struct TerminalCallbacks {
+    pub(crate) cursor_shape: CursorShape,
+    /// Window title set by the child with OSC 0 or 2.
+    pub(crate) title: Option<String>,
+    pub(crate) passthrough: Vec<Passthrough>,
+    /// Passed through sequences not yet written to the real terminal.
+    pub(crate) host_output: String,
+    /// Lines scrolled into the scrollback of the main screen so far, counted
+    /// by the output pump. Keeps growing once a scrollback of two lines or
+    /// more is full.
+    pub(crate) scrolled: usize,
+    /// URI, line and column where the hyperlink being written started.
+    open_link: Option<(String, usize, u16)>,
+    links: VecDeque<Link>,
+}
+
+/// An OSC 8 hyperlink, split into the rows it covered when it was closed.
+struct Link {
+    uri: String,
+    alternate: bool,
+    segments: Vec<Segment>,
+}
+
+/// The part of a hyperlink on one line, with the text it covered so that
+/// links whose text was overwritten or scrolled away are not drawn.
+struct Segment {
+    /// Line counted from the first line that ever scrolled into the
+    /// scrollback.
+    line: usize,
+    cols: Range<u16>,
+    text: String,
+}
+
+impl TerminalCallbacks {
+    pub(crate) fn new(passthrough: Vec<Passthrough>) -> Self {
+        TerminalCallbacks {
+            passthrough,
+            ..TerminalCallbacks::default()
+        }
+    }
+
+    /// Returns the hyperlinks visible in `rows` of `screen`.
+    pub(crate) fn hyperlinks(&self, screen: &Screen, rows: Range<u16>) -> Vec<Hyperlink> {
+        let top = self.scrolled.saturating_sub(screen.scrollback());
+        let mut hyperlinks = Vec::new();
+
+        for link in &self.links {
+            if link.alternate != screen.alternate_screen() {
+                continue;
+            }
+            for segment in &link.segments {
+                let Some(row) = segment.line.checked_sub(top) else {
+                    continue;
+                };
+                if (rows.start as usize..rows.end as usize).contains(&row)
+                    && row_text(screen, row as u16, segment.cols.clone()) == segment.text
+                {
+                    hyperlinks.push(Hyperlink {
+                        row: row as u16,
+                        cols: segment.cols.clone(),
+                        uri: link.uri.clone(),
+                    });
+                }
+            }
+        }
+
+        hyperlinks
+    }
+
+    fn passes(&self, kind: Passthrough) -> bool {
+        self.passthrough.contains(&kind)
+    }
+
+    /// Ends the open hyperlink at the cursor and starts a new one unless
+    /// `uri` is empty.
+    fn hyperlink(&mut self, screen: &mut Screen, uri: String) {
+        let scrolled = self.scrolled;
+        let (row, col) = screen.cursor_position();
+        let line = scrolled + row as usize;
+
+        if let Some((uri, start_line, start_col)) = self.open_link.take() {
+            let offset = screen.scrollback();
+            screen.set_scrollback(0);
+            let (_, cols) = screen.size();
+            let segments = (start_line.max(scrolled)..=line)
+                .map(|segment_line| {
+                    let start = if segment_line == start_line {
+                        start_col
+                    } else {
+                        0
+                    };
+                    let end = if segment_line == line {
+                        col.min(cols)
+                    } else {
+                        cols
+                    };
+                    let row = (segment_line - scrolled) as u16;
+                    Segment {
+                        line: segment_line,
+                        cols: start..end,
+                        text: row_text(screen, row, start..end),
+                    }
+                })
+                .filter(|segment| !segment.cols.is_empty())
+                .collect::<Vec<_>>();
+            screen.set_scrollback(offset);
+
+            if !segments.is_empty() {
+                if self.links.len() == MAX_HYPERLINKS {
+                    self.links.pop_front();
+                }
+                self.links.push_back(Link {
+                    uri,
+                    alternate: screen.alternate_screen(),
+                    segments,
+                });
+            }
+        }
+
+        if !uri.is_empty() {
+            self.open_link = Some((uri, line, col));
+        }
+    }
+}
+
+impl vt100::Callbacks for TerminalCallbacks {
+    fn audible_bell(&mut self, _: &mut Screen) {
+        if self.passes(Passthrough::Bell) {
+            self.host_output.push('\x07');
+        }
+    }
+
+    fn set_window_title(&mut self, _: &mut Screen, title: &[u8]) {
+        let title = String::from_utf8_lossy(title);
+        self.title = (!title.is_empty()).then(|| title.into_owned());
+    }
+
+    fn copy_to_clipboard(&mut self, _: &mut Screen, ty: &[u8], data: &[u8]) {
+        if self.passes(Passthrough::Clipboard) {
+            self.host_output.push_str(&format!(
+                "\x1b]52;{};{}\x07",
+                String::from_utf8_lossy(ty),
+                String::from_utf8_lossy(data)
+            ));
+        }
+    }
+
+    fn unhandled_csi(
+        &mut self,
+        _: &mut Screen,
+        i1: Option<u8>,
+        i2: Option<u8>,
+        params: &[&[u16]],
+        c: char,
+    ) {
+        // DECSCUSR, `CSI Ps SP q`
+        if (i1, i2, c) == (Some(b' '), None, 'q') {
+            let param = params.first().and_then(|p| p.first()).copied();
+            if let Some(shape) = CursorShape::from_param(param.unwrap_or(0)) {
+                self.cursor_shape = shape;
+            }
+        }
+    }
+
+    fn unhandled_osc(&mut self, screen: &mut Screen, params: &[&[u8]]) {
+        match params {
+            // Titles containing `;` arrive split into several parameters
+            [b"0" | b"2", title @ ..] => self.set_window_title(screen, &title.join(&b';')),
+            // `OSC 8 ; params ; URI`, where the URI may contain `;` as well
+            [b"8", _, uri @ ..] => {
+                let uri = String::from_utf8_lossy(&uri.join(&b';')).into_owned();
+                self.hyperlink(screen, uri);
+            }
+            [b"9", ..] | [b"777", b"notify", ..] if self.passes(Passthrough::Notifications) => {
+                let sequence = String::from_utf8_lossy(&params.join(&b';')).into_owned();
+                self.host_output.push_str(&format!("\x1b]{}\x07", sequence));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Number of scrollback lines above the screen, leaving the scroll position
+/// as it was.
+pub(crate) fn history_len(screen: &mut Screen) -> usize {
+    let offset = screen.scrollback();
+    screen.set_scrollback(usize::MAX);
+    let len = screen.scrollback();
+    screen.set_scrollback(offset);
+    len
+}
+
+/// The contents of `cols` of a visible row.
+fn row_text(screen: &Screen, row: u16, cols: Range<u16>) -> String {
+    cols.filter_map(|col| screen.cell(row, col))
+        .map(|cell| cell.contents())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vt100::Parser;
+
+    fn terminal(passthrough: Vec<Passthrough>) -> Parser<TerminalCallbacks> {
+        Parser::new_with_callbacks(3, 10, 0, TerminalCallbacks::new(passthrough))
+    }
+
+    fn hyperlinks(parser: &Parser<TerminalCallbacks>) -> Vec<Hyperlink> {
+        let (rows, _) = parser.screen().size();
+        parser.callbacks().hyperlinks(parser.screen(), 0..rows)
+    }
+
+    #[test]
+    fn splits_hyperlinks_into_rows() {
+        let mut parser = terminal(Vec::new());
+        parser.process(b"ab\x1b]8;id=1;https://x.test/a;b\x1b\\cdefghijklm\x1b]8;;\x07n");
+        assert_eq!(
+            hyperlinks(&parser),
+            [
+                Hyperlink::new(0, 2..10, "https://x.test/a;b"),
+                Hyperlink::new(1, 0..3, "https://x.test/a;b"),
+            ]
+        );
+
+        // Links whose text was overwritten are not drawn
+        parser.process(b"\x1b[1;3Hxy");
+        assert_eq!(
+            hyperlinks(&parser),
+            [Hyperlink::new(1, 0..3, "https://x.test/a;b")]
+        );
+    }
+
+    #[test]
+    fn hyperlinks_follow_scrolled_lines() {
+        // Without a scrollback only the count of the output pump tells how
+        // far the link moved up
+        let mut parser = terminal(Vec::new());
+        parser.process(b"\r\n\r\n\x1b]8;;https://x.test\x07link\x1b]8;;\x07");
+        parser.process(b"\r\n");
+        parser.callbacks_mut().scrolled += 1;
+        assert_eq!(
+            hyperlinks(&parser),
+            [Hyperlink::new(1, 0..4, "https://x.test")]
+        );
+    }
+
+    #[test]
+    fn passes_through_only_enabled_sequences() {
+        let output = b"\x07\x1b]52;c;aGk=\x07\x1b]9;done\x07\x1b]777;notify;title;body\x07";
+
+        let mut parser = terminal(Vec::new());
+        parser.process(output);
+        assert_eq!(parser.callbacks().host_output, "");
+
+        let mut parser = terminal(vec![Passthrough::Clipboard, Passthrough::Notifications]);
+        parser.process(output);
+        assert_eq!(
+            parser.callbacks().host_output,
+            "\x1b]52;c;aGk=\x07\x1b]9;done\x07\x1b]777;notify;title;body\x07"
+        );
+
+        let mut parser = terminal(vec![Passthrough::Bell]);
+        parser.process(output);
+        assert_eq!(parser.callbacks().host_output, "\x07");
+    }
+
+    #[test]
+    fn parses_cursor_shapes() {
+        let mut parser = terminal(Vec::new());
+        parser.process(b"\x1b[6 q");
+        assert_eq!(parser.callbacks().cursor_shape, CursorShape::SteadyBar);
+        parser.process(b"\x1b[9 q\x1b[3q");
+        assert_eq!(parser.callbacks().cursor_shape, CursorShape::SteadyBar);
+        parser.process(b"\x1b[3 q");
+        assert_eq!(
+            parser.callbacks().cursor_shape,
+            CursorShape::BlinkingUnderline
+        );
+        parser.process(b"\x1b[ q");
+        assert_eq!(parser.callbacks().cursor_shape, CursorShape::Default);
+    }
+
+    #[test]
+    fn sets_titles_with_semicolons() {
+        let mut parser = terminal(Vec::new());
+        parser.process(b"\x1b]2;a;b\x07");
+        assert_eq!(parser.callbacks().title.as_deref(), Some("a;b"));
+        parser.process(b"\x1b]0;\x07");
+        assert_eq!(parser.callbacks().title, None);
+    }
+}
@@ -0,0 +1,83 @@
+use portable_pty::ExitStatus;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Something that happened to the child, delivered to receivers obtained
+/// from [`VirtualTerminalBuilder::subscribe`](crate::VirtualTerminalBuilder::subscribe).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum TerminalEvent {
+    /// Raw bytes the child wrote to the PTY, already fed into the parser.
+    Output { bytes: Vec<u8>, time: Instant },
+    /// The virtual terminal was resized.
+    Resize { rows: u16, cols: u16, time: Instant },
+    /// The child exited.
+    Exit { status: ExitStatus, time: Instant },
+}
+
+impl TerminalEvent {
+    /// When the event happened. Output is stamped as soon as it is read
+    /// from the PTY, before it waits for the parser.
+    pub fn time(&self) -> Instant {
+        match self {
+            TerminalEvent::Output { time, .. }
+            | TerminalEvent::Resize { time, .. }
+            | TerminalEvent::Exit { time, .. } => *time,
+        }
+    }
+}
+
+/// How often an [`EventThread`] checks whether it was asked to finish.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Consumer of subscribed events, driven by an [`EventThread`].
+pub(crate) trait EventSink: Send + 'static {
+    fn event(&mut self, event: TerminalEvent) -> io::Result<()>;
+
+    /// Called once the queued events are handled after finishing was
+    /// requested.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Background thread feeding events from a subscription into a sink, so
+/// slow writers never hold up the PTY reader.
+pub(crate) struct EventThread {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl EventThread {
+    pub(crate) fn spawn(events: Receiver<TerminalEvent>, mut sink: impl EventSink) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                loop {
+                    match events.recv_timeout(POLL_INTERVAL) {
+                        Ok(event) => sink.event(event)?,
+                        Err(RecvTimeoutError::Timeout) if stop.load(Ordering::Acquire) => break,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                sink.finish()
+            })
+        };
+
+        EventThread { stop, thread }
+    }
+
+    /// Handles the events that are still queued and stops the thread.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Release);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("event thread panicked")))
+    }
+}
@@ -0,0 +1,558 @@
+use crossterm::event::{
+    Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use vt100::{MouseProtocolEncoding, MouseProtocolMode};
+
+/// Terminal modes requested by the child that change how input is encoded.
+#[derive(Default)]
+pub(crate) struct InputModes {
+    application_cursor: AtomicBool,
+    bracketed_paste: AtomicBool,
+    mouse: Mutex<(MouseProtocolMode, MouseProtocolEncoding)>,
+}
+
+impl InputModes {
+    pub(crate) fn update(&self, screen: &vt100::Screen) {
+        self.application_cursor
+            .store(screen.application_cursor(), Ordering::Relaxed);
+        self.bracketed_paste
+            .store(screen.bracketed_paste(), Ordering::Relaxed);
+        *self.mouse.lock().unwrap() = (
+            screen.mouse_protocol_mode(),
+            screen.mouse_protocol_encoding(),
+        );
+    }
+}
+
+/// Cloneable handle for writing input into the child's PTY.
+#[derive(Clone)]
+pub struct PtyInput {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    modes: Arc<InputModes>,
+}
+
+impl PtyInput {
+    pub(crate) fn new(writer: Box<dyn Write + Send>, modes: Arc<InputModes>) -> Self {
+        PtyInput {
+            writer: Arc::new(Mutex::new(writer)),
+            modes,
+        }
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(bytes)?;
+        writer.flush()
+    }
+
+    /// Encodes a crossterm event and writes it to the child, ignoring events
+    /// that have no byte representation.
+    pub fn send_event(&self, event: &Event) -> std::io::Result<()> {
+        let bytes = match event {
+            Event::Key(key) => {
+                encode_key(key, self.modes.application_cursor.load(Ordering::Relaxed))
+            }
+            Event::Paste(text) => Some(encode_paste(
+                text,
+                self.modes.bracketed_paste.load(Ordering::Relaxed),
+            )),
+            _ => None,
+        };
+
+        match bytes {
+            Some(bytes) => self.write_bytes(&bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Reports a mouse event at `row`/`col` of the child's screen, if the
+    /// child asked for this kind of event.
+    pub fn send_mouse(&self, event: &MouseEvent, row: u16, col: u16) -> std::io::Result<()> {
+        let (mode, encoding) = *self.modes.mouse.lock().unwrap();
+        match encode_mouse(event, row, col, mode, encoding) {
+            Some(bytes) => self.write_bytes(&bytes),
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn encode_key(key: &KeyEvent, application_cursor: bool) -> Option<Vec<u8>> {
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+
+    let alt = key.modifiers.contains(KeyModifiers::ALT);
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+    let mut bytes = match key.code {
+        KeyCode::Char(c) => {
+            if ctrl {
+                vec![ctrl_char(c)?]
+            } else {
+                c.to_string().into_bytes()
+            }
+        }
+        KeyCode::Enter => vec![b'\r'],
+        KeyCode::Tab => vec![b'\t'],
+        KeyCode::BackTab => b"\x1b[Z".to_vec(),
+        KeyCode::Backspace => {
+            if ctrl {
+                vec![0x08]
+            } else {
+                vec![0x7f]
+            }
+        }
+        KeyCode::Esc => vec![0x1b],
+        KeyCode::Null => vec![0x00],
+        KeyCode::Up => cursor_key(b'A', key.modifiers, application_cursor),
+        KeyCode::Down => cursor_key(b'B', key.modifiers, application_cursor),
+        KeyCode::Right => cursor_key(b'C', key.modifiers, application_cursor),
+        KeyCode::Left => cursor_key(b'D', key.modifiers, application_cursor),
+        KeyCode::Home => cursor_key(b'H', key.modifiers, application_cursor),
+        KeyCode::End => cursor_key(b'F', key.modifiers, application_cursor),
+        KeyCode::Insert => tilde_key(2, key.modifiers),
+        KeyCode::Delete => tilde_key(3, key.modifiers),
+        KeyCode::PageUp => tilde_key(5, key.modifiers),
+        KeyCode::PageDown => tilde_key(6, key.modifiers),
+        KeyCode::F(n) => function_key(n, key.modifiers)?,
+        _ => return None,
+    };
+
+    // Alt is sent as an ESC prefix for keys that don't carry modifiers in
+    // their own sequence.
+    if alt && !bytes.starts_with(b"\x1b[") && !bytes.starts_with(b"\x1bO") {
+        bytes.insert(0, 0x1b);
+    }
+
+    Some(bytes)
+}
+
+/// Parses a key name like `Enter`, `F5`, `a` or `Ctrl-c`, with any number of
+/// `Ctrl-`, `Alt-` and `Shift-` prefixes.
+pub(crate) fn parse_key(name: &str) -> Option<KeyEvent> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = name;
+    while let Some((prefix, key)) = rest.split_once('-').filter(|(_, key)| !key.is_empty()) {
+        modifiers |= match prefix.to_ascii_lowercase().as_str() {
+            "ctrl" | "c" => KeyModifiers::CONTROL,
+            "alt" | "meta" | "m" => KeyModifiers::ALT,
+            "shift" | "s" => KeyModifiers::SHIFT,
+            _ => break,
+        };
+        rest = key;
+    }
+
+    let mut chars = rest.chars();
+    let code = match (chars.next(), chars.next()) {
+        // Terminals send shifted letters as the uppercase character
+        (Some(c), None) if modifiers.contains(KeyModifiers::SHIFT) => {
+            let mut upper = c.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(upper), None) => KeyCode::Char(upper),
+                _ => KeyCode::Char(c),
+            }
+        }
+        (Some(c), None) => KeyCode::Char(c),
+        _ => match rest.to_ascii_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "tab" if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "esc" | "escape" => KeyCode::Esc,
+            "backspace" => KeyCode::Backspace,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "insert" => KeyCode::Insert,
+            "delete" | "del" => KeyCode::Delete,
+            name => KeyCode::F(name.strip_prefix('f')?.parse().ok()?),
+        },
+    };
+    Some(KeyEvent::new(code, modifiers))
+}
+
+pub(crate) fn encode_paste(text: &str, bracketed: bool) -> Vec<u8> {
+    let text = text.replace("\r\n", "\r").replace('\n', "\r");
+
+    if bracketed {
+        format!("\x1b[200~{}\x1b[201~", text).into_bytes()
+    } else {
+        text.into_bytes()
+    }
+}
+
+/// Encodes a mouse event at the zero-based `row`/`col` as xterm reports it
+/// for the given tracking `mode` and `encoding`. Returns `None` for events
+/// the mode doesn't report and for positions the encoding can't express.
+pub(crate) fn encode_mouse(
+    event: &MouseEvent,
+    row: u16,
+    col: u16,
+    mode: MouseProtocolMode,
+    encoding: MouseProtocolEncoding,
+) -> Option<Vec<u8>> {
+    let (mut code, release) = match event.kind {
+        MouseEventKind::Down(button) => (button_code(button), false),
+        MouseEventKind::Up(button) => (button_code(button), true),
+        MouseEventKind::Drag(button) => (button_code(button) + 32, false),
+        MouseEventKind::Moved => (3 + 32, false),
+        MouseEventKind::ScrollUp => (64, false),
+        MouseEventKind::ScrollDown => (65, false),
+        MouseEventKind::ScrollLeft => (66, false),
+        MouseEventKind::ScrollRight => (67, false),
+    };
+    let motion = code & 32 != 0;
+
+    let reported = match mode {
+        MouseProtocolMode::None => false,
+        MouseProtocolMode::Press => !release && !motion,
+        MouseProtocolMode::PressRelease => !motion,
+        MouseProtocolMode::ButtonMotion => event.kind != MouseEventKind::Moved,
+        MouseProtocolMode::AnyMotion => true,
+    };
+    if !reported {
+        return None;
+    }
+
+    // X10 compatibility mode doesn't report modifiers
+    if mode != MouseProtocolMode::Press {
+        if event.modifiers.contains(KeyModifiers::SHIFT) {
+            code += 4;
+        }
+        if event.modifiers.contains(KeyModifiers::ALT) {
+            code += 8;
+        }
+        if event.modifiers.contains(KeyModifiers::CONTROL) {
+            code += 16;
+        }
+    }
+
+    let (x, y) = (u32::from(col) + 1, u32::from(row) + 1);
+    if encoding == MouseProtocolEncoding::Sgr {
+        let end = if release { 'm' } else { 'M' };
+        return Some(format!("\x1b[<{};{};{}{}", code, x, y, end).into_bytes());
+    }
+
+    // The legacy encodings can't tell which button was released
+    if release {
+        code |= 3;
+    }
+    let mut bytes = b"\x1b[M".to_vec();
+    for value in [u32::from(code), x, y] {
+        let value = value + 32;
+        match encoding {
+            MouseProtocolEncoding::Utf8 if value < 0x800 => {
+                let mut buf = [0; 2];
+                bytes.extend_from_slice(char::from_u32(value)?.encode_utf8(&mut buf).as_bytes());
+            }
+            MouseProtocolEncoding::Default if value < 0x100 => bytes.push(value as u8),
+            _ => return None,
+        }
+    }
+    Some(bytes)
+}
+
+fn button_code(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 0,
+        MouseButton::Middle => 1,
+        MouseButton::Right => 2,
+    }
+}
+
+fn ctrl_char(c: char) -> Option<u8> {
+    match c {
+        'a'..='z' => Some(c as u8 - b'a' + 1),
+        'A'..='Z' => Some(c as u8 - b'A' + 1),
+        '@' | ' ' | '2' => Some(0x00),
+        '[' | '3' => Some(0x1b),
+        '\\' | '4' => Some(0x1c),
+        ']' | '5' => Some(0x1d),
+        '^' | '6' => Some(0x1e),
+        '_' | '7' | '/' => Some(0x1f),
+        '?' | '8' => Some(0x7f),
+        _ => None,
+    }
+}
+
+/// xterm modifier parameter: 1 + shift(1) + alt(2) + ctrl(4).
+fn modifier_param(modifiers: KeyModifiers) -> u8 {
+    let mut param = 1;
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        param += 1;
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        param += 2;
+    }
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        param += 4;
+    }
+    param
+}
+
+fn cursor_key(code: u8, modifiers: KeyModifiers, application_cursor: bool) -> Vec<u8> {
+    let param = modifier_param(modifiers);
+    if param > 1 {
+        format!("\x1b[1;{}{}", param, code as char).into_bytes()
+    } else if application_cursor {
+        vec![0x1b, b'O', code]
+    } else {
+        vec![0x1b, b'[', code]
+    }
+}
+
+fn tilde_key(number: u8, modifiers: KeyModifiers) -> Vec<u8> {
+    let param = modifier_param(modifiers);
+    if param > 1 {
+        format!("\x1b[{};{}~", number, param).into_bytes()
+    } else {
+        format!("\x1b[{}~", number).into_bytes()
+    }
+}
+
+fn function_key(n: u8, modifiers: KeyModifiers) -> Option<Vec<u8>> {
+    let param = modifier_param(modifiers);
+    match n {
+        1..=4 => {
+            let code = (b'P' + n - 1) as char;
+            if param > 1 {
+                Some(format!("\x1b[1;{}{}", param, code).into_bytes())
+            } else {
+                Some(format!("\x1bO{}", code).into_bytes())
+            }
+        }
+        5 => Some(tilde_key(15, modifiers)),
+        6..=10 => Some(tilde_key(n + 11, modifiers)),
+        11..=12 => Some(tilde_key(n + 12, modifiers)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> Option<KeyEvent> {
+        Some(KeyEvent::new(code, modifiers))
+    }
+
+    #[test]
+    fn parses_key_names() {
+        assert_eq!(parse_key("Enter"), key(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(
+            parse_key("pagedown"),
+            key(KeyCode::PageDown, KeyModifiers::NONE)
+        );
+        assert_eq!(parse_key("F12"), key(KeyCode::F(12), KeyModifiers::NONE));
+        assert_eq!(
+            parse_key("space"),
+            key(KeyCode::Char(' '), KeyModifiers::NONE)
+        );
+        assert_eq!(parse_key("x"), key(KeyCode::Char('x'), KeyModifiers::NONE));
+        assert_eq!(parse_key("-"), key(KeyCode::Char('-'), KeyModifiers::NONE));
+    }
+
+    #[test]
+    fn parses_modifier_prefixes() {
+        assert_eq!(
+            parse_key("Ctrl-c"),
+            key(KeyCode::Char('c'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            parse_key("C-M-Up"),
+            key(KeyCode::Up, KeyModifiers::CONTROL | KeyModifiers::ALT)
+        );
+        assert_eq!(
+            parse_key("Alt--"),
+            key(KeyCode::Char('-'), KeyModifiers::ALT)
+        );
+        assert_eq!(
+            parse_key("Shift-Tab"),
+            key(KeyCode::BackTab, KeyModifiers::SHIFT)
+        );
+    }
+
+    #[test]
+    fn shifted_characters_are_uppercase() {
+        let shift_a = parse_key("Shift-a").unwrap();
+        assert_eq!(shift_a.code, KeyCode::Char('A'));
+        assert_eq!(encode_key(&shift_a, false), Some(b"A".to_vec()));
+        assert_eq!(parse_key("S-é").unwrap().code, KeyCode::Char('É'));
+        assert_eq!(parse_key("Shift-1").unwrap().code, KeyCode::Char('1'));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert_eq!(parse_key("Hyper-x"), None);
+        assert_eq!(parse_key("enterr"), None);
+        assert_eq!(parse_key("Fx"), None);
+        assert_eq!(parse_key(""), None);
+    }
+
+    fn encode(code: KeyCode, modifiers: KeyModifiers) -> Option<Vec<u8>> {
+        encode_key(&KeyEvent::new(code, modifiers), false)
+    }
+
+    #[test]
+    fn encodes_characters_and_control_keys() {
+        let none = KeyModifiers::NONE;
+        assert_eq!(encode(KeyCode::Char('é'), none), Some("é".into()));
+        assert_eq!(encode(KeyCode::Enter, none), Some(b"\r".to_vec()));
+        assert_eq!(encode(KeyCode::Backspace, none), Some(b"\x7f".to_vec()));
+        assert_eq!(encode(KeyCode::BackTab, none), Some(b"\x1b[Z".to_vec()));
+
+        let ctrl = KeyModifiers::CONTROL;
+        assert_eq!(encode(KeyCode::Char('c'), ctrl), Some(b"\x03".to_vec()));
+        assert_eq!(encode(KeyCode::Char(' '), ctrl), Some(b"\x00".to_vec()));
+        assert_eq!(encode(KeyCode::Char(']'), ctrl), Some(b"\x1d".to_vec()));
+        assert_eq!(encode(KeyCode::Backspace, ctrl), Some(b"\x08".to_vec()));
+        assert_eq!(encode(KeyCode::Char('1'), ctrl), None);
+    }
+
+    #[test]
+    fn alt_prefixes_keys_with_escape() {
+        let alt = KeyModifiers::ALT;
+        assert_eq!(encode(KeyCode::Char('x'), alt), Some(b"\x1bx".to_vec()));
+        assert_eq!(
+            encode(KeyCode::Char('x'), alt | KeyModifiers::CONTROL),
+            Some(b"\x1b\x18".to_vec())
+        );
+        // Sequences that carry modifiers themselves aren't prefixed
+        assert_eq!(encode(KeyCode::Up, alt), Some(b"\x1b[1;3A".to_vec()));
+    }
+
+    #[test]
+    fn encodes_cursor_keys_for_the_cursor_mode() {
+        let up = KeyEvent::new(KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!(encode_key(&up, false), Some(b"\x1b[A".to_vec()));
+        assert_eq!(encode_key(&up, true), Some(b"\x1bOA".to_vec()));
+
+        let ctrl_up = KeyEvent::new(KeyCode::Up, KeyModifiers::CONTROL);
+        assert_eq!(encode_key(&ctrl_up, true), Some(b"\x1b[1;5A".to_vec()));
+        let shift_end = KeyEvent::new(KeyCode::End, KeyModifiers::SHIFT);
+        assert_eq!(encode_key(&shift_end, false), Some(b"\x1b[1;2F".to_vec()));
+    }
+
+    #[test]
+    fn encodes_editing_and_function_keys() {
+        let none = KeyModifiers::NONE;
+        assert_eq!(encode(KeyCode::Delete, none), Some(b"\x1b[3~".to_vec()));
+        assert_eq!(
+            encode(KeyCode::PageUp, KeyModifiers::CONTROL),
+            Some(b"\x1b[5;5~".to_vec())
+        );
+        assert_eq!(encode(KeyCode::F(1), none), Some(b"\x1bOP".to_vec()));
+        assert_eq!(
+            encode(KeyCode::F(4), KeyModifiers::SHIFT),
+            Some(b"\x1b[1;2S".to_vec())
+        );
+        assert_eq!(encode(KeyCode::F(5), none), Some(b"\x1b[15~".to_vec()));
+        assert_eq!(encode(KeyCode::F(6), none), Some(b"\x1b[17~".to_vec()));
+        assert_eq!(encode(KeyCode::F(12), none), Some(b"\x1b[24~".to_vec()));
+        assert_eq!(encode(KeyCode::F(13), none), None);
+    }
+
+    #[test]
+    fn ignores_key_releases() {
+        let mut key = KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE);
+        key.kind = KeyEventKind::Release;
+        assert_eq!(encode_key(&key, false), None);
+    }
+
+    #[test]
+    fn encodes_pastes() {
+        assert_eq!(encode_paste("a\r\nb\nc", false), b"a\rb\rc");
+        assert_eq!(encode_paste("a\nb", true), b"\x1b[200~a\rb\x1b[201~");
+    }
+
+    fn mouse(kind: MouseEventKind, modifiers: KeyModifiers) -> MouseEvent {
+        MouseEvent {
+            kind,
+            column: 0,
+            row: 0,
+            modifiers,
+        }
+    }
+
+    #[test]
+    fn encodes_sgr_presses_and_releases() {
+        let press = mouse(MouseEventKind::Down(MouseButton::Left), KeyModifiers::NONE);
+        let release = mouse(
+            MouseEventKind::Up(MouseButton::Right),
+            KeyModifiers::CONTROL,
+        );
+        let encode = |event| {
+            encode_mouse(
+                event,
+                4,
+                299,
+                MouseProtocolMode::PressRelease,
+                MouseProtocolEncoding::Sgr,
+            )
+        };
+        assert_eq!(encode(&press), Some(b"\x1b[<0;300;5M".to_vec()));
+        assert_eq!(encode(&release), Some(b"\x1b[<18;300;5m".to_vec()));
+    }
+
+    #[test]
+    fn x10_mode_reports_presses_without_modifiers() {
+        let encode = |event| {
+            encode_mouse(
+                event,
+                0,
+                0,
+                MouseProtocolMode::Press,
+                MouseProtocolEncoding::Default,
+            )
+        };
+        let modifiers = KeyModifiers::SHIFT | KeyModifiers::ALT | KeyModifiers::CONTROL;
+        let press = mouse(MouseEventKind::Down(MouseButton::Middle), modifiers);
+        assert_eq!(encode(&press), Some(b"\x1b[M!!!".to_vec()));
+        let release = mouse(MouseEventKind::Up(MouseButton::Middle), modifiers);
+        assert_eq!(encode(&release), None);
+        let drag = mouse(MouseEventKind::Drag(MouseButton::Left), KeyModifiers::NONE);
+        assert_eq!(encode(&drag), None);
+    }
+
+    #[test]
+    fn legacy_encodings_reject_positions_out_of_range() {
+        let press = mouse(MouseEventKind::Down(MouseButton::Left), KeyModifiers::SHIFT);
+        let encode = |row, col, encoding| {
+            encode_mouse(&press, row, col, MouseProtocolMode::PressRelease, encoding)
+        };
+        assert_eq!(
+            encode(1, 222, MouseProtocolEncoding::Default),
+            Some(b"\x1b[M$\xff\"".to_vec())
+        );
+        assert_eq!(encode(1, 223, MouseProtocolEncoding::Default), None);
+        assert_eq!(
+            encode(0, 223, MouseProtocolEncoding::Utf8),
+            Some("\x1b[M$\u{100}!".as_bytes().to_vec())
+        );
+        assert_eq!(encode(0, 2015, MouseProtocolEncoding::Utf8), None);
+    }
+
+    #[test]
+    fn legacy_releases_do_not_name_the_button() {
+        let release = mouse(MouseEventKind::Up(MouseButton::Right), KeyModifiers::NONE);
+        assert_eq!(
+            encode_mouse(
+                &release,
+                0,
+                0,
+                MouseProtocolMode::PressRelease,
+                MouseProtocolEncoding::Default,
+            ),
+            Some(b"\x1b[M#!!".to_vec())
+        );
+    }
+}
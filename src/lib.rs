@@ -0,0 +1,35 @@
+//! Run a command in a virtual terminal and display its screen as a live panel
+//! at the bottom of the real terminal.
+
+mod builder;
+mod callbacks;
+mod event;
+mod input;
+mod log;
+mod record;
+mod render;
+mod replay;
+mod script;
+mod scroll;
+mod snapshot;
+mod stack;
+mod virtual_terminal;
+
+pub use builder::{VirtualTerminalBuilder, DEFAULT_TERM};
+pub use callbacks::Passthrough;
+pub use event::TerminalEvent;
+pub use input::PtyInput;
+pub use log::OutputLog;
+pub use record::Recorder;
+pub use render::{
+    Cursor, CursorShape, Frame, Hyperlink, InlineRenderer, NullRenderer, Renderer,
+    ScrollRegionRenderer, StatusLine, Style,
+};
+pub use replay::{Player, Recording};
+pub use script::Script;
+pub use scroll::Outcome;
+pub use snapshot::{snapshot, SnapshotFormat};
+pub use stack::PanelStack;
+pub use virtual_terminal::{exit_code, AltScreen, ResizeHandle, StatusPosition, VirtualTerminal};
+
+pub use portable_pty::{CommandBuilder, ExitStatus};
@@ -0,0 +1,240 @@
+use crate::event::{EventSink, EventThread, TerminalEvent};
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+use vt100::Parser;
+
+/// Lines kept in the scrollback of the plain text parser before it is
+/// rebuilt from its visible screen, bounding its memory use.
+const REBASE_LINES: usize = 1000;
+
+/// Writes everything a virtual terminal outputs to a log, either as the raw
+/// byte stream or as plain text. Every write is flushed, so the log can be
+/// followed with `tail -f`.
+pub struct OutputLog {
+    thread: EventThread,
+}
+
+impl OutputLog {
+    /// Logs the unmodified output bytes of `events` to `out`.
+    pub fn raw(out: impl Write + Send + 'static, events: Receiver<TerminalEvent>) -> Self {
+        OutputLog {
+            thread: EventThread::spawn(events, RawLog { out }),
+        }
+    }
+
+    /// Logs the output of `events` as plain text, without escape sequences,
+    /// for a terminal of `rows` x `cols`.
+    ///
+    /// The output is replayed on a separate virtual terminal and each line is
+    /// written once it scrolls off the top of the screen, so redrawn lines
+    /// and progress bars end up in the log as they were last shown. The rows
+    /// left on screen are written when the log is finished.
+    pub fn plain(
+        out: impl Write + Send + 'static,
+        rows: u16,
+        cols: u16,
+        events: Receiver<TerminalEvent>,
+    ) -> Self {
+        let sink = PlainLog {
+            out,
+            parser: Parser::new(rows, cols, usize::MAX),
+            logged: 0,
+            wrapped: false,
+        };
+        OutputLog {
+            thread: EventThread::spawn(events, sink),
+        }
+    }
+
+    /// Writes the events that are still queued and closes the log.
+    pub fn finish(self) -> io::Result<()> {
+        self.thread.finish()
+    }
+}
+
+struct RawLog<W> {
+    out: W,
+}
+
+impl<W: Write + Send + 'static> EventSink for RawLog<W> {
+    fn event(&mut self, event: TerminalEvent) -> io::Result<()> {
+        if let TerminalEvent::Output { bytes, .. } = event {
+            self.out.write_all(&bytes)?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+struct PlainLog<W> {
+    out: W,
+    parser: Parser,
+    /// Lines of the parser's scrollback already written.
+    logged: usize,
+    /// Whether the last written line continues on the next one.
+    wrapped: bool,
+}
+
+impl<W: Write + Send + 'static> EventSink for PlainLog<W> {
+    fn event(&mut self, event: TerminalEvent) -> io::Result<()> {
+        match event {
+            TerminalEvent::Output { bytes, .. } => {
+                self.parser.process(&bytes);
+                self.write_scrolled()?;
+                self.out.flush()
+            }
+            TerminalEvent::Resize { rows, cols, .. } => {
+                self.parser.screen_mut().set_size(rows, cols);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let screen = self.parser.screen();
+        let (_, cols) = screen.size();
+        let lines: Vec<_> = screen.rows(0, cols).collect();
+        let used = lines
+            .iter()
+            .rposition(|line| !line.trim().is_empty())
+            .map_or(0, |row| row + 1);
+
+        for (row, line) in lines.iter().take(used).enumerate() {
+            let wrapped = screen.row_wrapped(row as u16) && row + 1 < used;
+            write_line(&mut self.out, &mut self.wrapped, line, wrapped)?;
+        }
+        if self.wrapped {
+            self.out.write_all(b"\n")?;
+        }
+        self.out.flush()
+    }
+}
+
+impl<W: Write> PlainLog<W> {
+    /// Writes the lines that scrolled into the parser's scrollback since the
+    /// last call.
+    fn write_scrolled(&mut self) -> io::Result<()> {
+        let screen = self.parser.screen_mut();
+        let (rows, cols) = screen.size();
+
+        screen.set_scrollback(usize::MAX);
+        let scrollback_len = screen.scrollback();
+        let mut next = self.logged;
+        while next < scrollback_len {
+            screen.set_scrollback(scrollback_len - next);
+            let chunk = (scrollback_len - next).min(rows as usize);
+            for (row, line) in screen.rows(0, cols).take(chunk).enumerate() {
+                let wrapped = screen.row_wrapped(row as u16);
+                write_line(&mut self.out, &mut self.wrapped, &line, wrapped)?;
+            }
+            next += chunk;
+        }
+        screen.set_scrollback(0);
+        self.logged = scrollback_len;
+
+        // Replaying the visible screen into a fresh parser drops the
+        // scrollback that was already written. The alternate screen would
+        // not survive the round trip, but it never scrolls into the
+        // scrollback anyway.
+        if self.logged > REBASE_LINES && !screen.alternate_screen() {
+            let state = screen.state_formatted();
+            self.parser = Parser::new(rows, cols, usize::MAX);
+            self.parser.process(&state);
+            self.parser.screen_mut().set_scrollback(usize::MAX);
+            self.logged = self.parser.screen().scrollback();
+            self.parser.screen_mut().set_scrollback(0);
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes one screen row of text, ending the line unless the row wraps onto
+/// the next one.
+fn write_line(
+    out: &mut impl Write,
+    pending: &mut bool,
+    line: &str,
+    wrapped: bool,
+) -> io::Result<()> {
+    let line = if wrapped { line } else { line.trim_end() };
+    out.write_all(line.as_bytes())?;
+    if !wrapped {
+        out.write_all(b"\n")?;
+    }
+    *pending = wrapped;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn plain_log(rows: u16, cols: u16) -> PlainLog<Vec<u8>> {
+        PlainLog {
+            out: Vec::new(),
+            parser: Parser::new(rows, cols, usize::MAX),
+            logged: 0,
+            wrapped: false,
+        }
+    }
+
+    fn output(log: &mut PlainLog<Vec<u8>>, bytes: &[u8]) {
+        log.event(TerminalEvent::Output {
+            bytes: bytes.to_vec(),
+            time: Instant::now(),
+        })
+        .unwrap();
+    }
+
+    fn text(log: &PlainLog<Vec<u8>>) -> &str {
+        std::str::from_utf8(&log.out).unwrap()
+    }
+
+    #[test]
+    fn writes_lines_as_they_scroll_and_the_screen_when_finished() {
+        let mut log = plain_log(3, 10);
+        output(&mut log, b"1  \r\n\x1b[31m2\x1b[0m\r\n3\r\n4\r\n");
+        assert_eq!(text(&log), "1\n2\n");
+
+        // Redrawn lines are written as they were last shown
+        output(&mut log, b"5\rX\r\n\r\n\r\n");
+        log.finish().unwrap();
+        assert_eq!(text(&log), "1\n2\n3\n4\nX\n");
+    }
+
+    #[test]
+    fn joins_wrapped_lines() {
+        let mut log = plain_log(2, 4);
+        output(&mut log, b"abcdefghij\r\nk\r\n");
+        assert_eq!(text(&log), "abcdefghij\n");
+
+        // A line still wrapping when the log finishes is ended
+        output(&mut log, b"lmnop");
+        log.finish().unwrap();
+        assert_eq!(text(&log), "abcdefghij\nk\nlmnop\n");
+    }
+
+    #[test]
+    fn rebuilds_the_parser_without_losing_the_screen() {
+        let mut log = plain_log(3, 10);
+        let lines: String = (0..REBASE_LINES + 10)
+            .map(|line| format!("{}\r\n", line))
+            .collect();
+        output(&mut log, lines.as_bytes());
+        output(&mut log, b"\x1b[1;1Hedit\x1b[3;1Hlast");
+        assert!(log.logged < REBASE_LINES);
+
+        output(&mut log, b"\r\nnext");
+        log.finish().unwrap();
+        let expected: String = (0..REBASE_LINES + 8)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_eq!(
+            text(&log),
+            expected + &format!("edit\n{}\nlast\nnext\n", REBASE_LINES + 9)
+        );
+    }
+}
@@ -1,227 +1,750 @@
-use clap::Parser as ClapParser;
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
 use crossterm::{
-    cursor::MoveUp,
-    terminal::{Clear, ClearType},
-    ExecutableCommand,
+    cursor,
+    event::{self, DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste},
+    terminal, ExecutableCommand,
 };
-use portable_pty::{native_pty_system, Child, CommandBuilder, PtySize};
-use std::io::{stdout, Write};
-use std::io::{BufReader, Read};
-use vt100::{Cell, Color, Parser};
+use detach::{
+    exit_code, AltScreen, NullRenderer, Outcome, OutputLog, PanelStack, Passthrough, Player,
+    Recorder, Recording, ResizeHandle, Script, ScrollRegionRenderer, SnapshotFormat,
+    StatusPosition, VirtualTerminal, VirtualTerminalBuilder, DEFAULT_TERM,
+};
+use signal_hook::{
+    consts::{SIGCONT, SIGHUP, SIGINT, SIGKILL, SIGSTOP, SIGTERM, SIGTSTP, SIGWINCH},
+    iterator::Signals,
+};
+use std::fs::File;
+use std::io::{stdin, stdout, BufWriter, ErrorKind, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Signals forwarded to the commands, SIGTSTP as SIGSTOP. SIGTSTP and
+/// SIGCONT also stop and continue `detach`, all others shut it down.
+const FORWARDED_SIGNALS: [i32; 5] = [SIGINT, SIGTERM, SIGHUP, SIGTSTP, SIGCONT];
+
+#[derive(ClapParser, Debug)]
+#[command(author, version, long_about = None)]
+#[command(
+    about = "Execute a command in a virtual terminal and display its output live at the bottom of the terminal."
+)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
+    /// Command and its arguments; separate several commands with `:::` to
+    /// run them in stacked panels. Put commands named like a subcommand,
+    /// such as script(1), after `--`
+    #[arg(required_unless_present = "cmd", num_args = 1..)]
+    command: Vec<String>,
+
+    /// Additional command line to run in its own panel, split into words
+    /// like a shell would (repeatable)
+    #[arg(long, value_name = "COMMAND")]
+    cmd: Vec<String>,
+
+    /// Run each command as a script through `$SHELL -c`, so pipes,
+    /// redirections and globs work
+    #[arg(long)]
+    shell: bool,
+
+    /// Set an environment variable for the commands (repeatable)
+    #[arg(long, value_name = "KEY=VAL", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Start the commands with an empty environment, apart from TERM and
+    /// --env. Without PATH, commands have to be given by their full path
+    #[arg(long)]
+    env_clear: bool,
+
+    /// Working directory for the commands
+    #[arg(long, value_name = "DIR")]
+    cwd: Option<PathBuf>,
+
+    /// Terminal type advertised to the commands in TERM
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_TERM)]
+    term: String,
+
+    /// Virtual terminal rows, capped at the terminal height. The panel
+    /// shows the rows in use unless --height or --max-height limit it
+    #[arg(long, default_value_t = 24)]
+    rows: u16,
+
+    /// Fixed panel height in rows, showing the last rows of output up to
+    /// the cursor
+    #[arg(long, value_name = "ROWS", conflicts_with_all = ["min_height", "max_height"])]
+    height: Option<u16>,
+
+    /// Let the panel grow with the output up to this many rows, then show
+    /// the last rows up to the cursor
+    #[arg(long, value_name = "ROWS")]
+    max_height: Option<u16>,
 
-pub struct VirtualTerminal {
-    parser: Parser,
-    reader: BufReader<Box<dyn Read + Send>>,
-    _child: Box<dyn Child + Send + Sync>,
-    last_render_height: u16,
+    /// Keep the panel at least this many rows high, so it doesn't shrink
+    /// with the output
+    #[arg(long, value_name = "ROWS")]
+    min_height: Option<u16>,
+
+    /// Virtual terminal columns [default: terminal width]
+    #[arg(long)]
+    cols: Option<u16>,
+
+    /// Refresh interval in milliseconds
+    #[arg(long, default_value_t = 50)]
+    refresh_ms: u64,
+
+    /// Lines of output history kept for scroll mode and --on-exit dump
+    #[arg(long, default_value_t = 10_000)]
+    scrollback: usize,
+
+    /// Print lines into the terminal scrollback above the panel once they
+    /// scroll off the top of the virtual terminal
+    #[arg(long)]
+    print_finished_lines: bool,
+
+    /// Where to show a status line with the command, elapsed time and state
+    #[arg(long, value_enum, default_value_t = StatusPosition::Off)]
+    status: StatusPosition,
+
+    /// How to show programs that switch to the alternate screen, like vim,
+    /// less or htop
+    #[arg(long, value_enum, default_value_t = AltScreen::Fixed)]
+    alt_screen: AltScreen,
+
+    /// Sequences the commands may send through to this terminal, separated
+    /// by commas
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "KIND")]
+    passthrough: Vec<Passthrough>,
+
+    /// How to draw the panels on the terminal
+    #[arg(long, value_enum, default_value_t = RendererKind::Inline)]
+    renderer: RendererKind,
+
+    /// Record the session as an asciicast v2 file for asciinema
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Write the complete output as plain text to a log file
+    #[arg(long, value_name = "FILE")]
+    log: Option<PathBuf>,
+
+    /// Write the unmodified output bytes to a log file
+    #[arg(long, value_name = "FILE")]
+    log_raw: Option<PathBuf>,
+
+    /// What to leave on screen once the command exits
+    #[arg(long, value_enum, default_value_t = OnExit::Keep)]
+    on_exit: OnExit,
+
+    /// Milliseconds to wait after forwarding SIGINT, SIGTERM or SIGHUP before
+    /// killing the commands
+    #[arg(long, default_value_t = 2000)]
+    grace_ms: u64,
+
+    /// Run without drawing anything and print the final screen once the
+    /// command exits, e.g. for snapshot tests. The screen is --rows by
+    /// --cols (default 80) large
+    #[arg(long)]
+    headless: bool,
+
+    /// Format of the screen printed by --headless
+    #[arg(long, value_enum, default_value_t = SnapshotFormat::Text, requires = "headless")]
+    snapshot: SnapshotFormat,
+
+    /// With --headless, print the screen and kill the command after this
+    /// many milliseconds, exiting with 124
+    #[arg(long, requires = "headless")]
+    timeout_ms: Option<u64>,
 }
 
-pub fn cell_to_ansi(cell: &Cell) -> String {
-    let mut codes = Vec::new();
+impl Args {
+    /// Collects the commands given positionally and with `--cmd`, in order.
+    fn commands(&self) -> anyhow::Result<Vec<Vec<String>>> {
+        let mut commands = Vec::new();
+        if !self.command.is_empty() {
+            commands.extend(
+                self.command
+                    .split(|arg| arg == ":::")
+                    .map(<[String]>::to_vec),
+            );
+        }
+        for cmd in &self.cmd {
+            if self.shell {
+                commands.push(vec![cmd.clone()]);
+            } else {
+                commands.push(shell_words::split(cmd)?);
+            }
+        }
+
+        if commands.iter().any(Vec::is_empty) {
+            anyhow::bail!("empty command");
+        }
 
-    // --- Text attributes ---
-    if cell.bold() {
-        codes.push("1");
-    }
-    if cell.dim() {
-        codes.push("2");
+        if self.shell {
+            let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+            for command in &mut commands {
+                *command = vec![shell.clone(), "-c".to_string(), command.join(" ")];
+            }
+        }
+        Ok(commands)
     }
-    if cell.italic() {
-        codes.push("3");
+
+    /// Applies the command-line options to a new virtual terminal running
+    /// `command`.
+    fn builder(&self, command: &[String]) -> VirtualTerminalBuilder {
+        let mut builder = VirtualTerminal::builder(&command[0]).args(&command[1..]);
+        if self.env_clear {
+            builder = builder.env_clear();
+        }
+        builder = builder.term(&self.term);
+        for (key, value) in &self.env {
+            builder = builder.env(key, value);
+        }
+        if let Some(cwd) = &self.cwd {
+            builder = builder.cwd(cwd);
+        }
+        for kind in &self.passthrough {
+            builder = builder.passthrough(*kind);
+        }
+        if let Some(rows) = self.min_height.or(self.height) {
+            builder = builder.min_height(rows);
+        }
+        if let Some(rows) = self.max_height.or(self.height) {
+            builder = builder.max_height(rows);
+        }
+        builder
+            .scrollback(self.scrollback)
+            .print_finished_lines(self.print_finished_lines)
+            .alt_screen(self.alt_screen)
     }
-    if cell.underline() {
-        codes.push("4");
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VAL, got `{}`", s)),
     }
-    if cell.inverse() {
-        codes.push("7");
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(format!("expected a positive number, got `{}`", s)),
     }
+}
 
-    // --- Foreground ---
-    let fg = cell.fgcolor();
-    let fg_color = color_to_ansi_code(&fg, true);
-    codes.push(&fg_color);
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Play back a session recorded with --record, --log-raw or script(1)
+    Replay(ReplayArgs),
+    /// Drive a command with a script of steps that wait for text on its
+    /// screen, send keys and check the exit code, like expect(1)
+    Script(ScriptArgs),
+}
 
-    // --- Background ---
-    let bg = cell.bgcolor();
-    let bg_color = color_to_ansi_code(&bg, false);
-    codes.push(&bg_color);
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Asciicast or typescript file
+    file: PathBuf,
 
-    if codes.is_empty() {
-        // nothing special, use reset
-        "\x1b[0m".to_string()
-    } else {
-        format!("\x1b[{}m", codes.join(";"))
+    /// Timing file written by `script --timing` for a typescript
+    #[arg(long, value_name = "FILE")]
+    timing: Option<PathBuf>,
+
+    /// Playback speed multiplier
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+
+    /// Start paused, e.g. to step through the output with `.`
+    #[arg(long)]
+    paused: bool,
+
+    /// Panel height for typescripts that don't record their size
+    /// [default: terminal height]
+    #[arg(long)]
+    rows: Option<u16>,
+
+    /// Panel width for typescripts that don't record their size
+    /// [default: terminal width]
+    #[arg(long)]
+    cols: Option<u16>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ScriptArgs {
+    /// Script file with one step per line
+    file: PathBuf,
+
+    /// Virtual terminal rows
+    #[arg(long, default_value_t = 24)]
+    rows: u16,
+
+    /// Virtual terminal columns
+    #[arg(long, default_value_t = 80)]
+    cols: u16,
+
+    /// Terminal type advertised to the commands in TERM
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_TERM)]
+    term: String,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RendererKind {
+    /// Below the cursor, redrawn in place by moving back up over the last
+    /// frame
+    Inline,
+    /// In rows reserved at the bottom of the terminal with scroll margins,
+    /// so anything printed above scrolls without touching the panels
+    ScrollRegion,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnExit {
+    /// Keep the last frame of the panel
+    Keep,
+    /// Remove the panel entirely
+    Clear,
+    /// Print the full output history into the terminal scrollback
+    Dump,
+}
+
+/// Puts the real terminal into raw mode so keystrokes reach the child
+/// unprocessed, restoring it when dropped.
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn enable() -> anyhow::Result<Self> {
+        terminal::enable_raw_mode()?;
+        stdout().execute(EnableBracketedPaste)?;
+        Ok(RawModeGuard)
     }
 }
 
-fn color_to_ansi_code(color: &Color, is_foreground: bool) -> String {
-    match color {
-        Color::Default => {
-            if is_foreground {
-                "39".to_string()
-            } else {
-                "49".to_string()
-            }
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = stdout().execute(cursor::SetCursorStyle::DefaultUserShape);
+        let _ = stdout().execute(cursor::Show);
+        let _ = stdout().execute(DisableMouseCapture);
+        let _ = stdout().execute(DisableBracketedPaste);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Handles terminal events until `deadline`, so input is forwarded promptly
+/// while frames are still drawn at the refresh rate.
+fn pump_events(stack: &mut PanelStack, deadline: Instant) -> anyhow::Result<()> {
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if !event::poll(timeout)? {
+            return Ok(());
         }
-        Color::Idx(idx) => {
-            if is_foreground {
-                format!("38;5;{}", idx)
-            } else {
-                format!("48;5;{}", idx)
-            }
+        stack.handle_event(&event::read()?)?;
+    }
+}
+
+/// Bounds for the virtual terminal size derived from the command line.
+#[derive(Clone, Copy)]
+struct PanelLimits {
+    max_rows: u16,
+    fixed_cols: Option<u16>,
+    /// Number of stacked panels sharing the real terminal.
+    panels: u16,
+    /// Rows drawn around each panel for status and scroll mode lines.
+    status_rows: u16,
+}
+
+impl PanelLimits {
+    fn new(args: &Args, panels: usize, status: StatusPosition) -> Self {
+        PanelLimits {
+            // The virtual terminal is at least as tall as the panel may grow
+            max_rows: args.rows.max(args.max_height.or(args.height).unwrap_or(0)),
+            fixed_cols: args.cols,
+            panels: panels.max(1) as u16,
+            status_rows: 1 + (status == StatusPosition::Top) as u16,
         }
-        Color::Rgb(r, g, b) => {
-            if is_foreground {
-                format!("38;2;{};{};{}", r, g, b)
-            } else {
-                format!("48;2;{};{};{}", r, g, b)
+    }
+
+    /// Computes the virtual terminal size of each panel from the real
+    /// terminal size, sharing the rows above the cursor equally.
+    fn size(&self, term_size: (u16, u16)) -> (u16, u16) {
+        let (term_cols, term_rows) = term_size;
+        let rows_per_panel = term_rows.saturating_sub(1) / self.panels;
+        let rows = self
+            .max_rows
+            .min(rows_per_panel.saturating_sub(self.status_rows))
+            .max(1);
+        let cols = self.fixed_cols.unwrap_or(term_cols).max(1);
+        (rows, cols)
+    }
+}
+
+fn spawn_resize_listener(limits: PanelLimits, handles: Vec<ResizeHandle>) -> anyhow::Result<()> {
+    let mut signals = Signals::new([SIGWINCH])?;
+
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            let Ok(term_size) = terminal::size() else {
+                continue;
+            };
+            let (rows, cols) = limits.size(term_size);
+            for resize in &handles {
+                let _ = resize.resize(rows, cols);
             }
         }
-    }
+    });
+
+    Ok(())
 }
 
-impl VirtualTerminal {
-    pub fn spawn(command: CommandBuilder, rows: u16, cols: u16) -> anyhow::Result<Self> {
-        let pty_system = native_pty_system();
-        let pair = pty_system.openpty(PtySize {
-            rows: rows,
-            cols: cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        })?;
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    let code = match args.mode.take() {
+        Some(Mode::Replay(replay_args)) => replay(replay_args)?,
+        Some(Mode::Script(script_args)) => script(script_args)?,
+        None => run(args)?,
+    };
+    std::process::exit(code);
+}
 
-        let child = pair.slave.spawn_command(command)?;
+fn replay(args: ReplayArgs) -> anyhow::Result<i32> {
+    let refresh_interval = Duration::from_millis(50);
+    // Nothing could unpause the playback without keyboard input
+    if args.paused && !stdin().is_terminal() {
+        anyhow::bail!("--paused needs a terminal on stdin");
+    }
 
-        let reader = pair.master.try_clone_reader()?;
-        let parser = Parser::new(rows, cols, 0);
+    let bytes = std::fs::read(&args.file)?;
+    let recording = if bytes.trim_ascii_start().starts_with(b"{") {
+        Recording::from_asciicast(&String::from_utf8_lossy(&bytes))?
+    } else {
+        let timing = args.timing.map(std::fs::read_to_string).transpose()?;
+        let (term_cols, term_rows) = terminal::size().unwrap_or((80, 24));
+        let rows = args.rows.unwrap_or(term_rows.saturating_sub(2).max(1));
+        let cols = args.cols.unwrap_or(term_cols);
+        Recording::from_typescript(&bytes, timing.as_deref(), rows, cols)?
+    };
+
+    let mut player = Player::new(recording, args.file.display().to_string());
+    player.set_speed(args.speed);
+    player.set_paused(args.paused);
+
+    let raw_mode = if stdin().is_terminal() {
+        Some(RawModeGuard::enable()?)
+    } else {
+        None
+    };
+
+    let mut last_tick = Instant::now();
+    loop {
+        let now = Instant::now();
+        player.advance(now - last_tick);
+        last_tick = now;
+        // Recordings from a taller terminal keep to the one at hand, with
+        // room for the status line and the cursor below it
+        if let Ok((_, rows)) = terminal::size() {
+            player.set_max_height(Some(rows.saturating_sub(2).max(1)));
+        }
+        player.render()?;
+        if player.is_finished() && !player.is_paused() {
+            break;
+        }
 
-        Ok(VirtualTerminal {
-            parser,
-            reader: BufReader::new(reader),
-            _child: child,
-            last_render_height: 0,
-        })
+        let timeout = player
+            .until_next_event()
+            .map_or(refresh_interval, |next| next.min(refresh_interval));
+        if raw_mode.is_none() {
+            std::thread::sleep(timeout);
+        } else if event::poll(timeout)?
+            && let Outcome::Exit = player.handle_event(&event::read()?)
+        {
+            break;
+        }
     }
 
-    pub fn get_used_height(&self) -> u16 {
-        let screen = self.parser.screen();
-        let (rows, _) = screen.size();
+    Ok(0)
+}
 
-        for row in (0..rows).rev() {
-            if self.is_row_non_empty(row) {
-                return (row + 1).min(rows);
-            }
+fn script(args: ScriptArgs) -> anyhow::Result<i32> {
+    let text = std::fs::read_to_string(&args.file)?;
+    let (rows, cols) = (args.rows.max(1), args.cols.max(1));
+    let cwd = std::env::current_dir()?;
+
+    let spawn = |command: &[String]| {
+        VirtualTerminal::builder(&command[0])
+            .args(&command[1..])
+            .term(&args.term)
+            .cwd(&cwd)
+            .size(rows, cols)
+            .renderer(NullRenderer)
+            .spawn()
+    };
+    match Script::parse(&text).and_then(|script| script.run(spawn, &mut stdout())) {
+        Ok(()) => Ok(0),
+        Err(error) => {
+            eprintln!("{}: {}", args.file.display(), error);
+            Ok(1)
         }
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<i32> {
+    let refresh_interval = Duration::from_millis(args.refresh_ms);
+    let grace_period = Duration::from_millis(args.grace_ms);
+    let commands = args.commands()?;
+
+    // Stacked panels need their titles to tell them apart
+    let status = match args.status {
+        StatusPosition::Off if commands.len() > 1 => StatusPosition::Top,
+        status => status,
+    };
 
-        0
+    let limits = PanelLimits::new(&args, commands.len(), status);
+    let (rows, cols) = if args.headless {
+        (args.rows.max(1), args.cols.unwrap_or(80).max(1))
+    } else {
+        limits.size(terminal::size().unwrap_or((80, 24)))
+    };
+    let captures_output = args.record.is_some() || args.log.is_some() || args.log_raw.is_some();
+    if captures_output && commands.len() > 1 {
+        anyhow::bail!("--record, --log and --log-raw support a single command");
+    }
+    if args.headless && commands.len() > 1 {
+        anyhow::bail!("--headless supports a single command");
     }
 
-    fn is_row_non_empty(&self, row: u16) -> bool {
-        let screen = self.parser.screen();
-        let (_, cols) = screen.size();
+    let mut recorder = None;
+    let mut logs = Vec::new();
+    let panels = commands
+        .iter()
+        .map(|command| {
+            // The stack draws every panel with its own renderer
+            let mut builder = args
+                .builder(command)
+                .size(rows, cols)
+                .status(status)
+                .renderer(NullRenderer);
+            if let Some(path) = &args.record {
+                let out = BufWriter::new(File::create(path)?);
+                let title = shell_words::join(command);
+                recorder = Some(Recorder::start(
+                    out,
+                    rows,
+                    cols,
+                    &title,
+                    builder.subscribe(),
+                )?);
+            }
+            if let Some(path) = &args.log {
+                let out = BufWriter::new(File::create(path)?);
+                logs.push(OutputLog::plain(out, rows, cols, builder.subscribe()));
+            }
+            if let Some(path) = &args.log_raw {
+                let out = BufWriter::new(File::create(path)?);
+                logs.push(OutputLog::raw(out, builder.subscribe()));
+            }
+            builder.spawn()
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-        for col in 0..cols {
-            if let Some(cell) = screen.cell(row, col) {
-                if !cell.contents().is_empty() && cell.contents() != " " {
-                    return true;
-                }
+    let mut stack;
+    let mut raw_mode;
+    if args.headless {
+        stack = PanelStack::with_renderer(panels, Box::new(NullRenderer));
+        raw_mode = None;
+    } else {
+        spawn_resize_listener(limits, panels.iter().map(|vt| vt.resize_handle()).collect())?;
+        stack = match args.renderer {
+            RendererKind::Inline => PanelStack::new(panels),
+            RendererKind::ScrollRegion => {
+                PanelStack::with_renderer(panels, Box::new(ScrollRegionRenderer::new()))
             }
-        }
-        false
-    }
-
-    pub fn render(&mut self) -> anyhow::Result<usize> {
-        let mut buf = [0; 256];
-        let mut stdout = stdout();
-
-        match self.reader.read(&mut buf)? {
-            0 => return Ok(0), // EOF
-            n => {
-                self.parser.process(&buf[..n]);
-
-                let screen = self.parser.screen();
-                let (rows, cols) = screen.size();
-                let dynamic_height = self.get_used_height();
-                let render_rows = rows.min(dynamic_height);
-
-                let mut frame = String::new();
-                for row in 0..render_rows {
-                    for col in 0..cols {
-                        if let Some(cell) = screen.cell(row, col) {
-                            let ansi = cell_to_ansi(cell);
-                            frame.push_str(&ansi);
-                            if cell.has_contents() {
-                                frame.push_str(cell.contents());
-                            } else {
-                                frame.push(' ');
-                            }
-                        }
+        };
+        raw_mode = if stdin().is_terminal() {
+            Some(RawModeGuard::enable()?)
+        } else {
+            None
+        };
+    }
+    let interactive = raw_mode.is_some();
+
+    let mut signals = Signals::new(FORWARDED_SIGNALS)?;
+    let mut kill_deadline = None;
+    let mut killed = false;
+    let timeout = args
+        .timeout_ms
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let mut timed_out = false;
+
+    loop {
+        for signal in signals.pending() {
+            // The commands run in sessions of their own, whose orphaned
+            // process groups ignore SIGTSTP, so they are stopped for good
+            stack.signal(if signal == SIGTSTP { SIGSTOP } else { signal })?;
+            match signal {
+                SIGTSTP => {
+                    // Give the terminal back to the shell while stopped
+                    stack.clear()?;
+                    drop(raw_mode.take());
+                    unsafe { libc::raise(SIGSTOP) };
+                }
+                SIGCONT => {
+                    // The shell may have changed the terminal modes meanwhile
+                    if interactive {
+                        drop(raw_mode.take());
+                        raw_mode = Some(RawModeGuard::enable()?);
                     }
-                    frame.push('\n');
+                    stack.redraw();
                 }
-
-                // Move up to the *top of the previous frame*
-                if self.last_render_height > 0 {
-                    stdout.execute(MoveUp(self.last_render_height))?;
+                _ if kill_deadline.is_none() => {
+                    kill_deadline = Some(Instant::now() + grace_period);
                 }
+                _ => {}
+            }
+        }
+        if let Some(deadline) = kill_deadline
+            && !killed
+            && Instant::now() >= deadline
+        {
+            stack.signal(SIGKILL)?;
+            killed = true;
+        }
 
-                // Clear everything from here down
-                stdout.execute(Clear(ClearType::FromCursorDown))?;
-
-                // Print the new frame
-                print!("{}", frame);
-                stdout.flush()?;
-
-                // Save new render height
-                self.last_render_height = render_rows;
+        // Descendants that escaped the process group may keep the PTY open
+        // after the kill, so stop waiting for its end of output
+        let finished = stack.is_finished() || (killed && stack.has_exited());
+        if finished {
+            stack.exit_scroll_mode()?;
+            stack.wait();
+        }
+        stack.render()?;
+        if finished {
+            break;
+        }
+        if let Some(timeout) = timeout
+            && Instant::now() >= timeout
+        {
+            timed_out = true;
+            break;
+        }
 
-                Ok(n)
-            }
+        if raw_mode.is_some() {
+            pump_events(&mut stack, Instant::now() + refresh_interval)?;
+        } else {
+            std::thread::sleep(refresh_interval);
         }
     }
-}
 
-#[derive(ClapParser, Debug)]
-#[command(author, version, long_about = None)]
-#[command(
-    about = "Execute a command in a virtual terminal and display its output live at the bottom of the terminal."
-)]
-struct Args {
-    /// Command and its arguments
-    #[arg(required = true, num_args = 1..)]
-    cmd: Vec<String>,
+    if args.headless {
+        let snapshot = stack.panels()[0].snapshot(args.snapshot);
+        let mut out = stdout().lock();
+        let written = write!(out, "{}", snapshot).and_then(|()| out.flush());
+        if timed_out {
+            stack.signal(SIGKILL)?;
+        }
+        // A reader that stops early, like `head`, still got what it wanted
+        match written {
+            Err(error) if error.kind() != ErrorKind::BrokenPipe => return Err(error.into()),
+            _ => {}
+        }
+    }
 
-    /// Virtual terminal rows
-    #[arg(long, default_value_t = 24)]
-    rows: u16,
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+    for log in logs {
+        log.finish()?;
+    }
+    if timed_out {
+        return Ok(124);
+    }
 
-    /// Virtual terminal columns
-    #[arg(long, default_value_t = 80)]
-    cols: u16,
+    // An interrupted run never leaves a half-finished panel behind
+    let on_exit = match args.on_exit {
+        OnExit::Keep if kill_deadline.is_some() => OnExit::Clear,
+        on_exit => on_exit,
+    };
+    match on_exit {
+        OnExit::Keep => {}
+        OnExit::Clear => stack.clear()?,
+        OnExit::Dump => stack.dump_history()?,
+    }
 
-    /// Refresh interval in milliseconds
-    #[arg(long, default_value_t = 50)]
-    refresh_ms: u64,
+    // Report the first command that failed, like `&&` would
+    let code = stack
+        .wait()
+        .iter()
+        .map(exit_code)
+        .find(|&code| code != 0)
+        .unwrap_or(0);
+    Ok(code)
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
 
-    let refresh_interval = std::time::Duration::from_millis(args.refresh_ms);
+    fn args(argv: &[&str]) -> Args {
+        Args::try_parse_from([&["detach"], argv].concat()).unwrap()
+    }
 
-    let command = &args.cmd[0];
-    let command_args = &args.cmd[1..];
+    fn commands(argv: &[&str]) -> anyhow::Result<Vec<Vec<String>>> {
+        args(argv).commands()
+    }
+
+    #[test]
+    fn verifies_the_command_line_definition() {
+        Args::command().debug_assert();
+    }
 
-    let mut cmd = CommandBuilder::new(command);
-    for arg in command_args {
-        cmd.arg(arg);
+    #[test]
+    fn parses_environment_assignments() {
+        assert_eq!(
+            args(&["--env", "A=1=2", "--env", "B=", "true"]).env,
+            [
+                ("A".to_string(), "1=2".to_string()),
+                ("B".to_string(), String::new())
+            ]
+        );
+        for bad in ["A", "=1"] {
+            assert!(Args::try_parse_from(["detach", "--env", bad, "true"]).is_err());
+        }
     }
 
-    let mut vt = VirtualTerminal::spawn(cmd, args.rows, args.cols)?;
+    #[test]
+    fn splits_commands_into_panels() {
+        assert_eq!(
+            commands(&["--cmd", "echo 'a b' c", "--", "ls", "-l", ":::", "top"]).unwrap(),
+            [vec!["ls", "-l"], vec!["top"], vec!["echo", "a b", "c"]]
+        );
+        assert_eq!(
+            commands(&["--", "script", "-q", "out"]).unwrap(),
+            [vec!["script", "-q", "out"]]
+        );
+        assert!(Args::try_parse_from(["detach", "script", "-q", "out"]).is_err());
+    }
 
-    while vt.render()? > 0 {
-        std::thread::sleep(refresh_interval);
+    #[test]
+    fn rejects_empty_commands() {
+        for argv in [
+            &["ls", ":::"][..],
+            &["ls", ":::", ":::", "top"],
+            &["--cmd", ""],
+            &["--cmd", "'unclosed"],
+        ] {
+            assert!(commands(argv).is_err(), "{:?}", argv);
+        }
     }
 
-    Ok(())
+    #[test]
+    fn wraps_commands_in_the_shell() {
+        let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+        assert_eq!(
+            commands(&["--shell", "--cmd", "ls | wc -l", "echo", "*"]).unwrap(),
+            [
+                vec![shell.clone(), "-c".to_string(), "echo *".to_string()],
+                vec![shell, "-c".to_string(), "ls | wc -l".to_string()],
+            ]
+        );
+    }
 }
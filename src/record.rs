@@ -0,0 +1,119 @@
+use crate::event::{EventSink, EventThread, TerminalEvent};
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Writes the output of a virtual terminal as an asciinema asciicast v2
+/// recording, timestamped with the time each event happened.
+///
+/// Every event is flushed right away, so a recording cut short by a crash
+/// still replays up to that point.
+pub struct Recorder {
+    thread: EventThread,
+}
+
+impl Recorder {
+    /// Writes the header for a `rows` x `cols` terminal to `out` and starts
+    /// recording `events` on a background thread.
+    pub fn start(
+        mut out: impl Write + Send + 'static,
+        rows: u16,
+        cols: u16,
+        title: &str,
+        events: Receiver<TerminalEvent>,
+    ) -> io::Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        writeln!(
+            out,
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"title\": {}}}",
+            cols,
+            rows,
+            timestamp,
+            serde_json::to_string(title)?
+        )?;
+        out.flush()?;
+
+        let sink = Asciicast {
+            out,
+            started: Instant::now(),
+            pending: Vec::new(),
+        };
+        Ok(Recorder {
+            thread: EventThread::spawn(events, sink),
+        })
+    }
+
+    /// Writes the events that are still queued and stops recording.
+    pub fn finish(self) -> io::Result<()> {
+        self.thread.finish()
+    }
+}
+
+struct Asciicast<W> {
+    out: W,
+    started: Instant,
+    /// Bytes of a character split across reads.
+    pending: Vec<u8>,
+}
+
+impl<W: Write + Send + 'static> EventSink for Asciicast<W> {
+    fn event(&mut self, event: TerminalEvent) -> io::Result<()> {
+        let time = event
+            .time()
+            .saturating_duration_since(self.started)
+            .as_secs_f64();
+        match event {
+            TerminalEvent::Output { bytes, .. } => {
+                let text = decode_utf8(&mut self.pending, &bytes);
+                if text.is_empty() {
+                    return Ok(());
+                }
+                writeln!(
+                    self.out,
+                    "[{:.6}, \"o\", {}]",
+                    time,
+                    serde_json::to_string(&text)?
+                )?;
+            }
+            TerminalEvent::Resize { rows, cols, .. } => {
+                writeln!(self.out, "[{:.6}, \"r\", \"{}x{}\"]", time, cols, rows)?;
+            }
+            _ => return Ok(()),
+        }
+        self.out.flush()
+    }
+}
+
+/// Appends `bytes` to `pending` and returns the longest prefix that is
+/// complete UTF-8, keeping a character split across reads for the next call.
+/// Invalid sequences become U+FFFD.
+fn decode_utf8(pending: &mut Vec<u8>, bytes: &[u8]) -> String {
+    pending.extend_from_slice(bytes);
+    let mut text = String::new();
+
+    loop {
+        match std::str::from_utf8(pending) {
+            Ok(valid) => {
+                text.push_str(valid);
+                pending.clear();
+                return text;
+            }
+            Err(error) => {
+                let valid = error.valid_up_to();
+                text.push_str(&String::from_utf8_lossy(&pending[..valid]));
+                match error.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        pending.drain(..valid + len);
+                    }
+                    None => {
+                        pending.drain(..valid);
+                        return text;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1337 @@
+use crossterm::{cursor, terminal};
+use std::io::{stdout, Write};
+use std::ops::Range;
+use unicode_width::UnicodeWidthStr;
+use vt100::{Cell, Color, MouseProtocolMode, Screen};
+
+/// Everything a renderer needs to draw one panel. Several frames are
+/// stacked top to bottom when multiple commands run at once.
+#[non_exhaustive]
+pub struct Frame<'a> {
+    pub screen: &'a Screen,
+    /// First screen row to show.
+    pub top: u16,
+    /// Number of screen rows to show, starting at `top`.
+    pub rows: u16,
+    pub header: Option<&'a StatusLine>,
+    pub footer: Option<&'a StatusLine>,
+    /// The child's cursor, for the frame that receives keyboard input.
+    pub cursor: Option<Cursor>,
+    /// Whether the frame takes over the whole host terminal instead of
+    /// being drawn inline.
+    pub fullscreen: bool,
+    /// Mouse events the frame wants reported, captured on the host terminal
+    /// while it is shown.
+    pub mouse: MouseProtocolMode,
+    pub hyperlinks: &'a [Hyperlink],
+}
+
+impl<'a> Frame<'a> {
+    /// A frame showing all of `screen` without status lines or a cursor.
+    pub fn new(screen: &'a Screen) -> Self {
+        Frame {
+            screen,
+            top: 0,
+            rows: screen.size().0,
+            header: None,
+            footer: None,
+            cursor: None,
+            fullscreen: false,
+            mouse: MouseProtocolMode::None,
+            hyperlinks: &[],
+        }
+    }
+}
+
+/// An OSC 8 hyperlink covering some cells of a screen row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Hyperlink {
+    pub row: u16,
+    pub cols: Range<u16>,
+    pub uri: String,
+}
+
+impl Hyperlink {
+    pub fn new(row: u16, cols: Range<u16>, uri: impl Into<String>) -> Self {
+        Hyperlink {
+            row,
+            cols,
+            uri: uri.into(),
+        }
+    }
+}
+
+/// Position and appearance of a virtual terminal's cursor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Cursor {
+    pub row: u16,
+    pub col: u16,
+    /// False while the child has hidden the cursor.
+    pub visible: bool,
+    pub shape: CursorShape,
+}
+
+impl Cursor {
+    /// A visible cursor of the host terminal's default shape.
+    pub fn new(row: u16, col: u16) -> Self {
+        Cursor {
+            row,
+            col,
+            visible: true,
+            shape: CursorShape::Default,
+        }
+    }
+}
+
+/// Cursor shapes selectable with DECSCUSR (`CSI Ps SP q`), in the order of
+/// their parameter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CursorShape {
+    /// The host terminal's configured shape.
+    #[default]
+    Default,
+    BlinkingBlock,
+    SteadyBlock,
+    BlinkingUnderline,
+    SteadyUnderline,
+    BlinkingBar,
+    SteadyBar,
+}
+
+impl CursorShape {
+    pub fn from_param(param: u16) -> Option<Self> {
+        Some(match param {
+            0 => CursorShape::Default,
+            1 => CursorShape::BlinkingBlock,
+            2 => CursorShape::SteadyBlock,
+            3 => CursorShape::BlinkingUnderline,
+            4 => CursorShape::SteadyUnderline,
+            5 => CursorShape::BlinkingBar,
+            6 => CursorShape::SteadyBar,
+            _ => return None,
+        })
+    }
+
+    /// The DECSCUSR sequence selecting this shape.
+    pub fn to_ansi(self) -> String {
+        format!("\x1b[{} q", self as u8)
+    }
+}
+
+/// Backend that draws the panel onto the host terminal.
+pub trait Renderer: Send {
+    /// Draws `frames` stacked top to bottom, replacing whatever was drawn
+    /// before.
+    fn render(&mut self, frames: &[Frame<'_>]) -> anyhow::Result<()>;
+
+    /// Removes the panel from the host terminal.
+    fn clear(&mut self) -> anyhow::Result<()>;
+
+    /// Prints `text` into the host terminal's normal scrollback above the
+    /// panel. The panel is drawn again on the next frame.
+    fn print_above(&mut self, text: &str) -> anyhow::Result<()>;
+
+    /// Maps a zero-based position on the host terminal to the index of the
+    /// last drawn frame showing it and the row and column on that frame's
+    /// screen. Renderers that can't tell never forward mouse events.
+    fn locate(&mut self, _row: u16, _col: u16) -> Option<(usize, u16, u16)> {
+        None
+    }
+
+    /// Writes sequences the child meant for the host terminal, like
+    /// clipboard writes or the bell, without drawing anything.
+    fn passthrough(&mut self, _sequences: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Graphic rendition of a cell, compared between adjacent cells so SGR
+/// sequences are only emitted when something actually changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+}
+
+impl Style {
+    pub fn from_cell(cell: &Cell) -> Self {
+        Style {
+            fg: cell.fgcolor(),
+            bg: cell.bgcolor(),
+            bold: cell.bold(),
+            dim: cell.dim(),
+            italic: cell.italic(),
+            underline: cell.underline(),
+            inverse: cell.inverse(),
+        }
+    }
+
+    pub fn to_ansi(self) -> String {
+        // Always start from a reset so attributes of the previous cell don't
+        // leak into this one
+        let mut codes = vec!["0"];
+
+        // --- Text attributes ---
+        if self.bold {
+            codes.push("1");
+        }
+        if self.dim {
+            codes.push("2");
+        }
+        if self.italic {
+            codes.push("3");
+        }
+        if self.underline {
+            codes.push("4");
+        }
+        if self.inverse {
+            codes.push("7");
+        }
+
+        // --- Foreground ---
+        let fg_color = color_to_ansi_code(&self.fg, true);
+        if self.fg != Color::Default {
+            codes.push(&fg_color);
+        }
+
+        // --- Background ---
+        let bg_color = color_to_ansi_code(&self.bg, false);
+        if self.bg != Color::Default {
+            codes.push(&bg_color);
+        }
+
+        format!("\x1b[{}m", codes.join(";"))
+    }
+}
+
+fn color_to_ansi_code(color: &Color, is_foreground: bool) -> String {
+    match color {
+        Color::Default => {
+            if is_foreground {
+                "39".to_string()
+            } else {
+                "49".to_string()
+            }
+        }
+        Color::Idx(idx) => {
+            if is_foreground {
+                format!("38;5;{}", idx)
+            } else {
+                format!("48;5;{}", idx)
+            }
+        }
+        Color::Rgb(r, g, b) => {
+            if is_foreground {
+                format!("38;2;{};{};{}", r, g, b)
+            } else {
+                format!("48;2;{};{};{}", r, g, b)
+            }
+        }
+    }
+}
+
+/// A line drawn by detach itself above or below the panel, with text
+/// aligned to both edges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StatusLine {
+    pub left: String,
+    pub right: String,
+    pub style: Style,
+}
+
+impl StatusLine {
+    pub fn new(left: impl Into<String>, right: impl Into<String>, style: Style) -> Self {
+        StatusLine {
+            left: left.into(),
+            right: right.into(),
+            style,
+        }
+    }
+
+    fn draw(&self, out: &mut String, cols: u16) {
+        let cols = cols as usize;
+        let mut left = truncate(&self.left, cols);
+        let right = truncate(&self.right, cols.saturating_sub(left.width()));
+        let padding = cols.saturating_sub(left.width() + right.width());
+        left.push_str(&" ".repeat(padding));
+
+        out.push_str(&self.style.to_ansi());
+        out.push_str(&left);
+        out.push_str(&right);
+        out.push_str("\x1b[0m");
+    }
+}
+
+/// Cuts `text` down to at most `cols` columns. The width is measured on the
+/// kept prefix as a whole, since sequences like an emoji followed by VS16
+/// are wider than their characters added up.
+fn truncate(text: &str, cols: usize) -> String {
+    let end = text
+        .char_indices()
+        .map(|(i, c)| i + c.len_utf8())
+        .take_while(|&end| text[..end].width() <= cols)
+        .last()
+        .unwrap_or(0);
+    text[..end].to_string()
+}
+
+/// Renderer that draws nothing, for running commands headless.
+#[derive(Default)]
+pub struct NullRenderer;
+
+impl Renderer for NullRenderer {
+    fn render(&mut self, _frames: &[Frame<'_>]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn print_above(&mut self, _text: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A row of the host terminal as drawn by [`InlineRenderer`].
+#[derive(Clone, PartialEq)]
+enum Line {
+    /// Cells and the hyperlinks over some of their columns.
+    Cells(Vec<Cell>, Vec<(Range<u16>, String)>),
+    Status(StatusLine, u16),
+}
+
+/// Draws the panel inline below the cursor, remembering what was drawn last
+/// time so only changed cells are repainted.
+///
+/// The cursor is expected to stay where the previous frame left it: at
+/// column 0 directly below the panel, or on the child's cursor inside it.
+pub struct InlineRenderer {
+    out: Box<dyn Write + Send>,
+    last_lines: Vec<Line>,
+    last_width: u16,
+    /// Row of the panel the host cursor is on, the panel height when it is
+    /// parked below the panel.
+    cursor_row: u16,
+    modes: HostModes,
+    /// Lines of the last full-screen frame while the host terminal is
+    /// switched to its alternate screen.
+    fullscreen: Option<Vec<Line>>,
+    /// First line, first screen row, rows and columns of each frame's
+    /// screen in the last drawn frames.
+    layout: Vec<FrameSpan>,
+    /// Host row of the panel's first line, looked up when a mouse event
+    /// needs it and forgotten once anything is written.
+    top: Option<u16>,
+}
+
+impl Default for InlineRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer for InlineRenderer {
+    fn render(&mut self, frames: &[Frame<'_>]) -> anyhow::Result<()> {
+        let screen_cols = frames.iter().map(|f| f.screen.size().1).max();
+        let (host_cols, _) = terminal::size().unwrap_or((screen_cols.unwrap_or(80), 0));
+        let mut output = match frames {
+            [frame] if frame.fullscreen => self.fullscreen_output(frame, host_cols),
+            _ => self.leave_fullscreen_output() + &self.frame_output(frames, host_cols),
+        };
+        self.modes.capture_mouse(&mut output, frames_mouse(frames));
+        self.write(&output)
+    }
+
+    fn clear(&mut self) -> anyhow::Result<()> {
+        let output = self.leave_fullscreen_output() + &self.clear_output();
+        self.write(&output)
+    }
+
+    fn print_above(&mut self, text: &str) -> anyhow::Result<()> {
+        let mut output = self.leave_fullscreen_output() + &self.clear_output();
+        output.push_str(text);
+        self.write(&output)
+    }
+
+    fn passthrough(&mut self, sequences: &str) -> anyhow::Result<()> {
+        self.write(sequences)
+    }
+
+    fn locate(&mut self, row: u16, col: u16) -> Option<(usize, u16, u16)> {
+        let top = match self.fullscreen {
+            Some(_) => 0,
+            None => self.top()?,
+        };
+        locate(&self.layout, row.checked_sub(top)?, col)
+    }
+}
+
+impl InlineRenderer {
+    /// Creates a renderer that draws to stdout.
+    pub fn new() -> Self {
+        Self::with_writer(Box::new(stdout()))
+    }
+
+    pub fn with_writer(out: Box<dyn Write + Send>) -> Self {
+        InlineRenderer {
+            out,
+            last_lines: Vec::new(),
+            last_width: 0,
+            cursor_row: 0,
+            modes: HostModes::default(),
+            fullscreen: None,
+            layout: Vec::new(),
+            top: None,
+        }
+    }
+
+    fn write(&mut self, output: &str) -> anyhow::Result<()> {
+        if !output.is_empty() {
+            self.top = None;
+            self.out.write_all(output.as_bytes())?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Host row of the panel's first line, found from the host cursor
+    /// position.
+    fn top(&mut self) -> Option<u16> {
+        if self.top.is_none() {
+            let (_, row) = cursor::position().ok()?;
+            self.top = row.checked_sub(self.cursor_row);
+        }
+        self.top
+    }
+
+    /// Height of the last drawn frame in rows.
+    fn height(&self) -> u16 {
+        self.last_lines.len() as u16
+    }
+
+    /// Builds the output that erases the panel, leaving the cursor where its
+    /// top row was in its usual shape.
+    fn clear_output(&mut self) -> String {
+        let mut out = String::from("\r");
+        if self.cursor_row > 0 {
+            out.push_str(&format!("\x1b[{}A", self.cursor_row));
+        }
+        out.push_str("\x1b[J");
+        self.modes.park_cursor(&mut out);
+        self.modes.capture_mouse(&mut out, MouseProtocolMode::None);
+        self.last_lines.clear();
+        self.layout.clear();
+        self.cursor_row = 0;
+        out
+    }
+
+    /// Moves the host cursor from below the panel onto `cursor`, which
+    /// starts `top` rows into the panel.
+    fn place_cursor(&mut self, out: &mut String, top: u16, cursor: Cursor, host_cols: u16) {
+        if !cursor.visible {
+            self.modes.hide_cursor(out);
+            return;
+        }
+
+        let row = top + cursor.row;
+        let col = cursor.col.min(host_cols.saturating_sub(1));
+        out.push_str(&format!("\x1b[{}A\x1b[{}G", self.height() - row, col + 1));
+        self.cursor_row = row;
+        self.modes.show_cursor(out, cursor.shape);
+    }
+
+    /// Builds the output that draws `frame` over the whole host terminal,
+    /// switching it to its alternate screen first. The inline panel stays
+    /// untouched on the normal screen meanwhile.
+    fn fullscreen_output(&mut self, frame: &Frame<'_>, host_cols: u16) -> String {
+        let mut out = String::new();
+        let previous = self.fullscreen.take();
+        let lines = draw_fullscreen(&mut out, previous, frame, host_cols, &mut self.modes);
+
+        let (_, screen_cols) = frame.screen.size();
+        self.layout = vec![(0, frame.top, frame.rows, screen_cols.min(host_cols))];
+        self.fullscreen = Some(lines);
+        out
+    }
+
+    /// Builds the output that returns the host terminal to its normal
+    /// screen, where the inline panel and cursor are as they were left.
+    fn leave_fullscreen_output(&mut self) -> String {
+        match self.fullscreen.take() {
+            Some(_) => "\x1b[?1049l".to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Builds the output that updates the panel to `frames`, clipped to
+    /// `host_cols` columns.
+    fn frame_output(&mut self, frames: &[Frame<'_>], host_cols: u16) -> String {
+        let lines = frame_lines(frames, host_cols);
+        let mut out = String::new();
+
+        // A width change makes the host terminal re-wrap the previous frame,
+        // so it can no longer be patched in place
+        let full = host_cols != self.last_width;
+        let rows_above_cursor = if full {
+            let drawn_width = self
+                .last_lines
+                .iter()
+                .map(|line| match line {
+                    Line::Cells(cells, _) => cells.len() as u16,
+                    Line::Status(_, cols) => *cols,
+                })
+                .max()
+                .unwrap_or(0);
+            let wrapped_rows = drawn_width.div_ceil(host_cols.max(1)).max(1);
+            self.cursor_row.saturating_mul(wrapped_rows)
+        } else {
+            self.cursor_row
+        };
+
+        // Move up to the *top of the previous frame*
+        out.push('\r');
+        if rows_above_cursor > 0 {
+            out.push_str(&format!("\x1b[{}A", rows_above_cursor));
+        }
+        if full {
+            out.push_str("\x1b[J");
+            self.last_lines.clear();
+        }
+
+        let mut pen = Style::default();
+        for (row, line) in lines.iter().enumerate() {
+            draw_line(
+                &mut out,
+                self.last_lines.get(row),
+                line,
+                &mut pen,
+                host_cols,
+            );
+            out.push_str("\r\n");
+        }
+
+        if pen != Style::default() {
+            out.push_str("\x1b[0m");
+        }
+
+        // Clear rows left over from a taller previous frame
+        if lines.len() < self.last_lines.len() {
+            out.push_str("\x1b[J");
+        }
+
+        self.last_lines = lines;
+        self.last_width = host_cols;
+        self.cursor_row = self.height();
+
+        let (layout, cursor) = frame_layout(frames, 0, host_cols);
+        self.layout = layout;
+        match cursor {
+            Some((top, cursor)) => self.place_cursor(&mut out, top, cursor, host_cols),
+            None => self.modes.park_cursor(&mut out),
+        }
+
+        out
+    }
+}
+
+/// Draws the panel in rows reserved at the bottom of the host terminal, with
+/// the scroll region (DECSTBM) limited to the rows above. Whatever is written
+/// above the panel, by `detach` or by anything else sharing the terminal,
+/// scrolls within those rows and never runs into the panel or leaves
+/// fragments of it in the scrollback.
+///
+/// The host cursor stays in the scroll region for those writers, so the
+/// child's cursor is drawn as an inverted cell. The margins are reset when
+/// the panel is cleared and when the renderer is dropped, which leaves the
+/// last frame on screen with the cursor below it.
+pub struct ScrollRegionRenderer {
+    out: Box<dyn Write + Send>,
+    last_lines: Vec<Line>,
+    /// Host terminal size the margins were set for.
+    host_size: (u16, u16),
+    /// Rows reserved below the scroll region, 0 while no margins are set.
+    reserved: u16,
+    /// Host row and column of the child's cursor as last drawn.
+    soft_cursor: Option<(u16, u16)>,
+    modes: HostModes,
+    fullscreen: Option<Vec<Line>>,
+    /// Host row, first screen row, rows and columns of each frame's screen
+    /// in the last drawn frames.
+    layout: Vec<FrameSpan>,
+}
+
+impl Default for ScrollRegionRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer for ScrollRegionRenderer {
+    fn render(&mut self, frames: &[Frame<'_>]) -> anyhow::Result<()> {
+        let screen_cols = frames.iter().map(|f| f.screen.size().1).max();
+        let host_size = terminal::size().unwrap_or((screen_cols.unwrap_or(80), 24));
+        let mut output = match frames {
+            [frame] if frame.fullscreen => self.fullscreen_output(frame, host_size.0),
+            _ => self.leave_fullscreen_output() + &self.frame_output(frames, host_size),
+        };
+        self.modes.capture_mouse(&mut output, frames_mouse(frames));
+        self.write(&output)
+    }
+
+    fn clear(&mut self) -> anyhow::Result<()> {
+        let mut output = self.leave_fullscreen_output();
+        let (_, host_rows) = self.host_size;
+        output.push_str("\x1b7");
+        for row in host_rows - self.reserved..host_rows {
+            output.push_str(&format!("\x1b[{};1H\x1b[2K", row + 1));
+        }
+        output.push_str("\x1b8");
+        self.release(&mut output);
+        self.modes.park_cursor(&mut output);
+        self.modes
+            .capture_mouse(&mut output, MouseProtocolMode::None);
+        self.write(&output)
+    }
+
+    fn print_above(&mut self, text: &str) -> anyhow::Result<()> {
+        let mut output = self.leave_fullscreen_output();
+        output.push_str(text);
+        self.write(&output)
+    }
+
+    fn passthrough(&mut self, sequences: &str) -> anyhow::Result<()> {
+        self.write(sequences)
+    }
+
+    fn locate(&mut self, row: u16, col: u16) -> Option<(usize, u16, u16)> {
+        locate(&self.layout, row, col)
+    }
+}
+
+impl Drop for ScrollRegionRenderer {
+    fn drop(&mut self) {
+        let mut output = self.leave_fullscreen_output();
+        self.modes
+            .capture_mouse(&mut output, MouseProtocolMode::None);
+        if self.reserved > 0 {
+            // Continue on a new line below the panel
+            let (_, host_rows) = self.host_size;
+            self.release(&mut output);
+            output.push_str(&format!("\x1b[{};1H\r\n", host_rows));
+        }
+        self.modes.park_cursor(&mut output);
+        let _ = self.write(&output);
+    }
+}
+
+impl ScrollRegionRenderer {
+    /// Creates a renderer that draws to stdout.
+    pub fn new() -> Self {
+        Self::with_writer(Box::new(stdout()))
+    }
+
+    pub fn with_writer(out: Box<dyn Write + Send>) -> Self {
+        ScrollRegionRenderer {
+            out,
+            last_lines: Vec::new(),
+            host_size: (0, 0),
+            reserved: 0,
+            soft_cursor: None,
+            modes: HostModes::default(),
+            fullscreen: None,
+            layout: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, output: &str) -> anyhow::Result<()> {
+        if !output.is_empty() {
+            self.out.write_all(output.as_bytes())?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Resets the margins to the whole terminal.
+    fn release(&mut self, out: &mut String) {
+        if self.reserved > 0 {
+            out.push_str("\x1b7\x1b[r\x1b8");
+        }
+        self.reserved = 0;
+        self.host_size = (0, 0);
+        self.last_lines.clear();
+        self.layout.clear();
+        self.soft_cursor = None;
+    }
+
+    /// Reserves `height` rows at the bottom of a terminal of `host_size`.
+    /// Returns false if the rows reserved before can't be patched in place.
+    fn reserve(&mut self, out: &mut String, height: u16, host_size: (u16, u16)) -> bool {
+        let (_, host_rows) = host_size;
+        let keep = host_size == self.host_size;
+
+        if !keep && self.reserved > 0 {
+            // The resized terminal may have moved the panel anywhere below
+            // the cursor
+            out.push_str("\x1b7\x1b[r\x1b8\x1b[J");
+            self.reserved = 0;
+        }
+        if height > self.reserved {
+            // Scroll the region up far enough to uncover the new rows, with
+            // the cursor following its line
+            let rows = height - self.reserved;
+            out.push_str(&"\x1bD".repeat(rows as usize));
+            out.push_str(&format!("\x1b[{}A", rows));
+        } else {
+            // Rows given back to the scroll region start out blank
+            out.push_str("\x1b7");
+            for row in host_rows - self.reserved..host_rows - height {
+                out.push_str(&format!("\x1b[{};1H\x1b[2K", row + 1));
+            }
+            out.push_str("\x1b8");
+        }
+
+        // DECSTBM moves the cursor to the top left corner
+        out.push_str(&format!("\x1b7\x1b[1;{}r\x1b8", host_rows - height));
+        self.reserved = height;
+        self.host_size = host_size;
+        keep
+    }
+
+    /// Builds the output that updates the panel to `frames`, clipped to the
+    /// host terminal, leaving the cursor where it was.
+    fn frame_output(&mut self, frames: &[Frame<'_>], host_size: (u16, u16)) -> String {
+        let (host_cols, host_rows) = host_size;
+        let mut lines = frame_lines(frames, host_cols);
+        // Leave the scroll region at least two rows, the least DECSTBM takes
+        lines.truncate(host_rows.saturating_sub(2) as usize);
+        let height = lines.len() as u16;
+        let mut out = String::new();
+
+        if (height != self.reserved || host_size != self.host_size)
+            && !self.reserve(&mut out, height, host_size)
+        {
+            self.last_lines.clear();
+        }
+        out.push_str("\x1b7");
+
+        // The panel keeps its bottom edge, so rows line up from below
+        let top = host_rows - height;
+        let soft_cursor = self.soft_cursor.take();
+        let mut pen = Style::default();
+        for (row, line) in lines.iter().enumerate() {
+            let host_row = top + row as u16;
+            let previous = (row + self.last_lines.len())
+                .checked_sub(lines.len())
+                .and_then(|row| self.last_lines.get(row))
+                .filter(|previous| same_shape(previous, line))
+                .filter(|_| soft_cursor.is_none_or(|(row, _)| row != host_row));
+            if previous == Some(line) {
+                continue;
+            }
+            out.push_str(&format!("\x1b[{};1H", host_row + 1));
+            if previous.is_none() {
+                out.push_str("\x1b[2K");
+            }
+            draw_line(&mut out, previous, line, &mut pen, host_cols);
+        }
+        if pen != Style::default() {
+            out.push_str("\x1b[0m");
+        }
+
+        let (layout, cursor) = frame_layout(frames, top, host_cols);
+        let cursor = cursor
+            .filter(|(_, cursor)| cursor.visible)
+            .map(|(first, cursor)| (first + cursor.row, cursor.col))
+            .filter(|&(row, col)| row < host_rows && col < host_cols);
+        if let Some((row, col)) = cursor
+            && let Some(Line::Cells(cells, _)) = lines.get((row - top) as usize)
+            && let Some(cell) = cells.get(col as usize)
+        {
+            let style = Style::from_cell(cell);
+            let inverted = Style {
+                inverse: !style.inverse,
+                ..style
+            };
+            let contents = if cell.has_contents() {
+                cell.contents()
+            } else {
+                " "
+            };
+            out.push_str(&format!(
+                "\x1b[{};{}H{}{}\x1b[0m",
+                row + 1,
+                col + 1,
+                inverted.to_ansi(),
+                contents
+            ));
+            self.soft_cursor = Some((row, col));
+        }
+        out.push_str("\x1b8");
+
+        // Only one cursor should be seen at a time
+        match self.soft_cursor {
+            Some(_) => self.modes.hide_cursor(&mut out),
+            None => self.modes.park_cursor(&mut out),
+        }
+
+        self.last_lines = lines;
+        self.layout = layout;
+        out
+    }
+
+    /// Builds the output that draws `frame` over the whole host terminal on
+    /// its alternate screen.
+    fn fullscreen_output(&mut self, frame: &Frame<'_>, host_cols: u16) -> String {
+        let mut out = String::new();
+        let previous = match self.fullscreen.take() {
+            Some(previous) => previous,
+            None => {
+                // The margins stay in effect on the alternate screen, so
+                // they are reset until the panel is back
+                out.push_str("\x1b[?1049h\x1b[r\x1b[H\x1b[2J");
+                Vec::new()
+            }
+        };
+        let lines = draw_fullscreen(&mut out, Some(previous), frame, host_cols, &mut self.modes);
+
+        let (_, screen_cols) = frame.screen.size();
+        self.layout = vec![(0, frame.top, frame.rows, screen_cols.min(host_cols))];
+        self.fullscreen = Some(lines);
+        out
+    }
+
+    /// Builds the output that returns the host terminal to its normal
+    /// screen, where the panel and the cursor are as they were left, and
+    /// sets the margins again.
+    fn leave_fullscreen_output(&mut self) -> String {
+        if self.fullscreen.take().is_none() {
+            return String::new();
+        }
+        let mut out = "\x1b[?1049l".to_string();
+        let (_, host_rows) = self.host_size;
+        if self.reserved > 0 {
+            out.push_str(&format!("\x1b7\x1b[1;{}r\x1b8", host_rows - self.reserved));
+        }
+        self.modes.park_cursor(&mut out);
+        out
+    }
+}
+
+/// Cursor and mouse modes of the host terminal, changed only when they
+/// differ from what was set last.
+#[derive(Default)]
+struct HostModes {
+    cursor_hidden: bool,
+    cursor_shape: CursorShape,
+    mouse: MouseProtocolMode,
+}
+
+impl HostModes {
+    /// Shows the host cursor in its usual shape.
+    fn park_cursor(&mut self, out: &mut String) {
+        self.show_cursor(out, CursorShape::Default);
+    }
+
+    fn show_cursor(&mut self, out: &mut String, shape: CursorShape) {
+        if shape != self.cursor_shape {
+            out.push_str(&shape.to_ansi());
+            self.cursor_shape = shape;
+        }
+        if self.cursor_hidden {
+            out.push_str("\x1b[?25h");
+            self.cursor_hidden = false;
+        }
+    }
+
+    fn hide_cursor(&mut self, out: &mut String) {
+        if !self.cursor_hidden {
+            out.push_str("\x1b[?25l");
+            self.cursor_hidden = true;
+        }
+    }
+
+    /// Switches the host terminal's mouse tracking to `mode`, always with
+    /// SGR encoding so any position can be reported.
+    fn capture_mouse(&mut self, out: &mut String, mode: MouseProtocolMode) {
+        if mode == self.mouse {
+            return;
+        }
+        if let Some(previous) = mouse_tracking(self.mouse) {
+            out.push_str(&format!("\x1b[?{}l\x1b[?1006l", previous));
+        }
+        if let Some(tracking) = mouse_tracking(mode) {
+            out.push_str(&format!("\x1b[?{}h\x1b[?1006h", tracking));
+        }
+        self.mouse = mode;
+    }
+}
+
+/// The mouse events to capture for `frames`: those of the first frame that
+/// wants any.
+fn frames_mouse(frames: &[Frame<'_>]) -> MouseProtocolMode {
+    frames
+        .iter()
+        .map(|frame| frame.mouse)
+        .find(|&mode| mode != MouseProtocolMode::None)
+        .unwrap_or_default()
+}
+
+/// Host row of a frame's first shown screen row, then that screen row, the
+/// number of rows shown and the number of columns shown.
+type FrameSpan = (u16, u16, u16, u16);
+
+/// Returns where the screen of each of the stacked `frames` starts when the
+/// first line is drawn on host row `top`, and the host row of the first
+/// shown screen row of the frame holding the cursor with the cursor moved
+/// into the shown rows.
+fn frame_layout(
+    frames: &[Frame<'_>],
+    top: u16,
+    host_cols: u16,
+) -> (Vec<FrameSpan>, Option<(u16, Cursor)>) {
+    let mut layout = Vec::new();
+    let mut cursor = None;
+    let mut first = top;
+    for frame in frames {
+        first += frame.header.is_some() as u16;
+        let (_, screen_cols) = frame.screen.size();
+        layout.push((first, frame.top, frame.rows, screen_cols.min(host_cols)));
+        if let Some(frame_cursor) = frame.cursor.and_then(|c| shown_cursor(frame, c)) {
+            cursor = Some((first, frame_cursor));
+        }
+        first += frame.rows + frame.footer.is_some() as u16;
+    }
+    (layout, cursor)
+}
+
+/// Maps a host position relative to the panel's first line to the frame
+/// and screen position `layout` puts there.
+fn locate(layout: &[FrameSpan], line: u16, col: u16) -> Option<(usize, u16, u16)> {
+    layout
+        .iter()
+        .enumerate()
+        .find_map(|(index, &(first, top, rows, cols))| {
+            let row = line.checked_sub(first).filter(|&row| row < rows)?;
+            (col < cols).then_some((index, top + row, col))
+        })
+}
+
+/// Draws `frame` over the whole host terminal, switching it to its alternate
+/// screen first unless `previous` holds the lines already drawn there.
+/// Returns the lines drawn.
+fn draw_fullscreen(
+    out: &mut String,
+    previous: Option<Vec<Line>>,
+    frame: &Frame<'_>,
+    host_cols: u16,
+    modes: &mut HostModes,
+) -> Vec<Line> {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => {
+            out.push_str("\x1b[?1049h\x1b[H\x1b[2J");
+            Vec::new()
+        }
+    };
+
+    let lines = frame_lines(std::slice::from_ref(frame), host_cols);
+    let mut pen = Style::default();
+    for (row, line) in lines.iter().enumerate() {
+        if previous.get(row) == Some(line) {
+            continue;
+        }
+        out.push_str(&format!("\x1b[{};1H", row + 1));
+        draw_line(out, previous.get(row), line, &mut pen, host_cols);
+    }
+    if pen != Style::default() {
+        out.push_str("\x1b[0m");
+    }
+    if lines.len() < previous.len() {
+        out.push_str(&format!("\x1b[{};1H\x1b[J", lines.len() + 1));
+    }
+
+    match frame.cursor.and_then(|cursor| shown_cursor(frame, cursor)) {
+        Some(cursor) if cursor.visible => {
+            let col = cursor.col.min(host_cols.saturating_sub(1));
+            out.push_str(&format!("\x1b[{};{}H", cursor.row + 1, col + 1));
+            modes.show_cursor(out, cursor.shape);
+        }
+        _ => modes.hide_cursor(out),
+    }
+
+    lines
+}
+
+/// Whether `line` can be drawn over `previous` by repainting only what
+/// changed.
+fn same_shape(previous: &Line, line: &Line) -> bool {
+    match (previous, line) {
+        (Line::Cells(previous, _), Line::Cells(cells, _)) => previous.len() == cells.len(),
+        (Line::Status(_, previous), Line::Status(_, cols)) => previous == cols,
+        _ => false,
+    }
+}
+
+/// The xterm private mode that reports the events of `mode`. X10 mode is
+/// tracked like VT200 mode, its releases are dropped when encoding.
+fn mouse_tracking(mode: MouseProtocolMode) -> Option<u16> {
+    match mode {
+        MouseProtocolMode::None => None,
+        MouseProtocolMode::Press | MouseProtocolMode::PressRelease => Some(1000),
+        MouseProtocolMode::ButtonMotion => Some(1002),
+        MouseProtocolMode::AnyMotion => Some(1003),
+    }
+}
+
+/// Draws `line` over `previous` starting at column 0, repainting as little
+/// as possible.
+fn draw_line(
+    out: &mut String,
+    previous: Option<&Line>,
+    line: &Line,
+    pen: &mut Style,
+    host_cols: u16,
+) {
+    match (previous, line) {
+        (Some(Line::Cells(previous, previous_links)), Line::Cells(cells, links))
+            if previous.len() == cells.len() && previous_links == links =>
+        {
+            draw_changed(out, previous, cells, links, pen)
+        }
+        (Some(previous), line) if previous == line => {}
+        (_, Line::Cells(cells, links)) => draw_full(out, cells, links, pen),
+        (_, Line::Status(status, cols)) => {
+            status.draw(out, *cols);
+            if *cols < host_cols {
+                out.push_str("\x1b[K");
+            }
+        }
+    }
+}
+
+/// Moves `cursor` into the rows shown by `frame`, or returns `None` if it is
+/// outside them.
+fn shown_cursor(frame: &Frame<'_>, cursor: Cursor) -> Option<Cursor> {
+    let row = cursor.row.checked_sub(frame.top)?;
+    (row < frame.rows).then_some(Cursor { row, ..cursor })
+}
+
+/// Lays out the rows of the stacked frames, each clipped to `host_cols`.
+fn frame_lines(frames: &[Frame<'_>], host_cols: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+
+    for frame in frames {
+        let (_, screen_cols) = frame.screen.size();
+        let cols = screen_cols.min(host_cols);
+
+        if let Some(header) = frame.header {
+            lines.push(Line::Status(header.clone(), cols));
+        }
+        for row in frame.top..frame.top + frame.rows {
+            let cells = (0..cols)
+                .filter_map(|col| frame.screen.cell(row, col).cloned())
+                .collect();
+            let links = frame
+                .hyperlinks
+                .iter()
+                .filter(|link| link.row == row && link.cols.start < cols)
+                .map(|link| (link.cols.start..link.cols.end.min(cols), link.uri.clone()))
+                .collect();
+            lines.push(Line::Cells(cells, links));
+        }
+        if let Some(footer) = frame.footer {
+            lines.push(Line::Status(footer.clone(), cols));
+        }
+    }
+
+    lines
+}
+
+/// Formats the scrollback history followed by the first `rows` rows of the
+/// screen as plain lines, for printing into the host terminal's own
+/// scrollback.
+pub(crate) fn format_history(screen: &mut Screen, rows: u16) -> String {
+    let (_, cols) = screen.size();
+    let mut out = String::new();
+
+    walk_scrollback(screen, |screen, chunk| {
+        for row in 0..chunk {
+            push_line(&mut out, screen, row, cols);
+        }
+    });
+
+    let offset = screen.scrollback();
+    screen.set_scrollback(0);
+    for row in 0..rows {
+        push_line(&mut out, screen, row, cols);
+    }
+    screen.set_scrollback(offset);
+
+    out
+}
+
+/// Visits the scrollback history from the oldest line. The history can only
+/// be read through the visible window, so `f` is called once per screenful
+/// with the number of leading visible rows that hold the next lines.
+pub(crate) fn walk_scrollback(screen: &mut Screen, f: impl FnMut(&Screen, u16)) {
+    walk_recent_scrollback(screen, usize::MAX, f);
+}
+
+/// Like [`walk_scrollback`], but starts `lines` lines before the end of the
+/// history.
+pub(crate) fn walk_recent_scrollback(
+    screen: &mut Screen,
+    lines: usize,
+    mut f: impl FnMut(&Screen, u16),
+) {
+    let (screen_rows, _) = screen.size();
+    let offset = screen.scrollback();
+
+    screen.set_scrollback(lines);
+    let mut remaining = screen.scrollback();
+    while remaining > 0 {
+        let chunk = remaining.min(screen_rows as usize);
+        screen.set_scrollback(remaining);
+        f(screen, chunk as u16);
+        remaining -= chunk;
+    }
+
+    screen.set_scrollback(offset);
+}
+
+/// Formats a visible row of `screen` as a plain line for printing.
+pub(crate) fn format_line(screen: &Screen, row: u16) -> String {
+    let mut out = String::new();
+    push_line(&mut out, screen, row, screen.size().1);
+    out
+}
+
+fn push_line(out: &mut String, screen: &Screen, row: u16, cols: u16) {
+    let cells: Vec<Cell> = (0..cols)
+        .filter_map(|col| screen.cell(row, col).cloned())
+        .collect();
+    draw_full(out, &cells, &[], &mut Style::default());
+    out.push_str("\r\n");
+}
+
+fn set_pen(out: &mut String, pen: &mut Style, cell: &Cell) {
+    let style = Style::from_cell(cell);
+    if style != *pen {
+        out.push_str(&style.to_ansi());
+        *pen = style;
+    }
+}
+
+/// Writes a cell's contents and returns how many columns it occupies. Wide
+/// characters that would be cut off by the right edge are drawn as a space.
+fn push_contents(out: &mut String, cell: &Cell, cols_left: usize) -> usize {
+    if cell.is_wide() && cols_left < 2 {
+        out.push(' ');
+        1
+    } else if cell.has_contents() {
+        out.push_str(cell.contents());
+        if cell.is_wide() {
+            2
+        } else {
+            1
+        }
+    } else {
+        out.push(' ');
+        1
+    }
+}
+
+/// Draws a whole row, skipping trailing blank cells and clearing the rest of
+/// the line instead.
+fn draw_full(out: &mut String, cells: &[Cell], links: &[(Range<u16>, String)], pen: &mut Style) {
+    let used = cells
+        .iter()
+        .rposition(|cell| {
+            (cell.has_contents() && cell.contents() != " ")
+                || Style::from_cell(cell) != Style::default()
+        })
+        // A wide character also uses the cell it covers, which clearing
+        // the rest of the line would erase
+        .map_or(0, |col| {
+            (col + 1 + cells[col].is_wide() as usize).min(cells.len())
+        });
+
+    let mut link = None;
+    for (col, cell) in cells[..used].iter().enumerate() {
+        // The wide character before this cell already covers it
+        if cell.is_wide_continuation() {
+            continue;
+        }
+        set_pen(out, pen, cell);
+        set_link(out, &mut link, link_at(links, col));
+        push_contents(out, cell, cells.len() - col);
+    }
+    set_link(out, &mut link, None);
+
+    if *pen != Style::default() {
+        out.push_str("\x1b[0m");
+        *pen = Style::default();
+    }
+    if used < cells.len() {
+        out.push_str("\x1b[K");
+    }
+}
+
+/// Repaints only the cells of a row that differ from the previous frame.
+fn draw_changed(
+    out: &mut String,
+    previous: &[Cell],
+    cells: &[Cell],
+    links: &[(Range<u16>, String)],
+    pen: &mut Style,
+) {
+    let mut cursor_col = 0;
+    let mut link = None;
+
+    for (col, cell) in cells.iter().enumerate() {
+        if previous[col] == *cell || cell.is_wide_continuation() {
+            continue;
+        }
+
+        if cursor_col != col {
+            out.push_str(&format!("\x1b[{}G", col + 1));
+        }
+        set_pen(out, pen, cell);
+        set_link(out, &mut link, link_at(links, col));
+        cursor_col = col + push_contents(out, cell, cells.len() - col);
+    }
+    set_link(out, &mut link, None);
+}
+
+fn link_at(links: &[(Range<u16>, String)], col: usize) -> Option<&str> {
+    links
+        .iter()
+        .find(|(cols, _)| cols.contains(&(col as u16)))
+        .map(|(_, uri)| uri.as_str())
+}
+
+/// Opens, switches or closes the OSC 8 hyperlink around the next cell.
+fn set_link<'a>(out: &mut String, current: &mut Option<&'a str>, link: Option<&'a str>) {
+    if link != *current {
+        out.push_str(&format!("\x1b]8;;{}\x1b\\", link.unwrap_or_default()));
+        *current = link;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vt100::Parser;
+
+    fn row_cells(output: &str, cols: u16) -> Vec<Cell> {
+        let mut parser = Parser::new(2, cols, 0);
+        parser.process(output.as_bytes());
+        (0..cols)
+            .map(|col| parser.screen().cell(0, col).unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn wide_character_at_the_last_column_is_not_padded() {
+        let cells = row_cells("abcdefgh字", 10);
+        let mut out = String::new();
+        draw_full(&mut out, &cells, &[], &mut Style::default());
+        assert_eq!(out, "abcdefgh字");
+    }
+
+    #[test]
+    fn wide_character_cut_by_the_right_edge_is_drawn_as_a_space() {
+        let cells = row_cells("abcdefgh字", 10);
+        let mut out = String::new();
+        draw_full(&mut out, &cells[..9], &[], &mut Style::default());
+        assert_eq!(out, "abcdefgh ");
+    }
+
+    #[test]
+    fn changed_wide_character_skips_its_continuation() {
+        let previous = row_cells("ab", 6);
+        let cells = row_cells("a字b", 6);
+        let mut out = String::new();
+        draw_changed(&mut out, &previous, &cells, &[], &mut Style::default());
+        assert_eq!(out, "\x1b[2G字b");
+    }
+
+    fn scroll_region_renderer() -> ScrollRegionRenderer {
+        ScrollRegionRenderer::with_writer(Box::new(std::io::sink()))
+    }
+
+    #[test]
+    fn reserving_rows_scrolls_the_region_up() {
+        let mut renderer = scroll_region_renderer();
+        let mut out = String::new();
+        assert!(!renderer.reserve(&mut out, 3, (80, 24)));
+        assert_eq!(out, "\x1bD\x1bD\x1bD\x1b[3A\x1b7\x1b[1;21r\x1b8");
+        assert_eq!(renderer.reserved, 3);
+
+        // Growing on the same terminal only uncovers the extra rows
+        let mut out = String::new();
+        assert!(renderer.reserve(&mut out, 4, (80, 24)));
+        assert_eq!(out, "\x1bD\x1b[1A\x1b7\x1b[1;20r\x1b8");
+    }
+
+    #[test]
+    fn giving_rows_back_blanks_them() {
+        let mut renderer = scroll_region_renderer();
+        renderer.reserve(&mut String::new(), 3, (80, 24));
+        let mut out = String::new();
+        assert!(renderer.reserve(&mut out, 1, (80, 24)));
+        assert_eq!(
+            out,
+            "\x1b7\x1b[22;1H\x1b[2K\x1b[23;1H\x1b[2K\x1b8\x1b7\x1b[1;23r\x1b8"
+        );
+        assert_eq!(renderer.reserved, 1);
+    }
+
+    #[test]
+    fn resized_terminal_reserves_from_scratch() {
+        let mut renderer = scroll_region_renderer();
+        renderer.reserve(&mut String::new(), 3, (80, 24));
+        let mut out = String::new();
+        assert!(!renderer.reserve(&mut out, 2, (100, 30)));
+        assert_eq!(
+            out,
+            "\x1b7\x1b[r\x1b8\x1b[J\x1bD\x1bD\x1b[2A\x1b7\x1b[1;28r\x1b8"
+        );
+        assert_eq!(renderer.host_size, (100, 30));
+    }
+
+    #[test]
+    fn scroll_region_keeps_at_least_two_rows() {
+        let parser = Parser::new(10, 20, 0);
+        let mut renderer = scroll_region_renderer();
+        let output = renderer.frame_output(&[Frame::new(parser.screen())], (20, 6));
+        assert_eq!(renderer.reserved, 4);
+        assert!(output.contains("\x1b[1;2r"));
+    }
+
+    #[test]
+    fn status_line_fits_titles_with_variation_selectors() {
+        let heart = "\u{2764}\u{fe0f}";
+        let status = StatusLine::new(heart.repeat(8), " 00:01", Style::default());
+        for cols in 0..20 {
+            let mut out = String::new();
+            status.draw(&mut out, cols);
+            let text = out
+                .trim_start_matches(&Style::default().to_ansi())
+                .trim_end_matches("\x1b[0m");
+            assert!(text.width() <= cols as usize, "{} > {}", text.width(), cols);
+        }
+
+        assert_eq!(truncate(&heart.repeat(3), 5), heart.repeat(2) + "\u{2764}");
+    }
+}
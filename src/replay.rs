@@ -0,0 +1,631 @@
+use crate::event::TerminalEvent;
+use crate::render::{Frame, InlineRenderer, Renderer, StatusLine, Style};
+use crate::scroll::Outcome;
+use crate::virtual_terminal::{format_elapsed, shown_window};
+use anyhow::Context;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use vt100::{Color, MouseProtocolMode, Parser};
+
+/// Distance moved by the seek keys.
+const SEEK_STEP: Duration = Duration::from_secs(5);
+
+/// Range of playback speeds selectable with `+` and `-`.
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 64.0;
+
+/// A recorded session: the size of the terminal it started in and its output
+/// and resizes, timed relative to `started`.
+#[non_exhaustive]
+pub struct Recording {
+    pub rows: u16,
+    pub cols: u16,
+    pub started: Instant,
+    pub events: Vec<TerminalEvent>,
+}
+
+impl Recording {
+    pub fn new(rows: u16, cols: u16, started: Instant, events: Vec<TerminalEvent>) -> Self {
+        Recording {
+            rows,
+            cols,
+            started,
+            events,
+        }
+    }
+
+    /// Parses an asciinema asciicast v2 file, as written by `--record`.
+    pub fn from_asciicast(text: &str) -> anyhow::Result<Self> {
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+        let header: Value = serde_json::from_str(lines.next().unwrap_or_default())
+            .context("malformed asciicast header")?;
+        if header["version"].as_u64() != Some(2) {
+            anyhow::bail!("unsupported asciicast version {}", header["version"]);
+        }
+        let size = |key| {
+            header[key]
+                .as_u64()
+                .map_or(0, |n| n.min(u16::MAX.into()) as u16)
+        };
+        let (rows, cols) = (size("height").max(1), size("width").max(1));
+
+        let started = Instant::now();
+        let mut events = Vec::new();
+        for (number, line) in lines.enumerate() {
+            let event: Value = serde_json::from_str(line)
+                .with_context(|| format!("malformed event on line {}", number + 2))?;
+            let (Some(time), Some(code), Some(data)) =
+                (event[0].as_f64(), event[1].as_str(), event[2].as_str())
+            else {
+                anyhow::bail!("malformed event on line {}", number + 2);
+            };
+
+            let time = started + Duration::from_secs_f64(time.max(0.0));
+            match code {
+                "o" => events.push(TerminalEvent::Output {
+                    bytes: data.as_bytes().to_vec(),
+                    time,
+                }),
+                "r" => {
+                    let Some((cols, rows)) = data
+                        .split_once('x')
+                        .and_then(|(c, r)| Some((c.parse().ok()?, r.parse().ok()?)))
+                    else {
+                        anyhow::bail!("malformed resize on line {}", number + 2);
+                    };
+                    events.push(TerminalEvent::Resize { rows, cols, time });
+                }
+                // Input and markers don't change the screen
+                _ => {}
+            }
+        }
+
+        Ok(Recording::new(rows, cols, started, events))
+    }
+
+    /// Parses a typescript written by `script(1)` or `--log-raw`, replayed
+    /// on a `rows` x `cols` terminal unless the `script` header names a
+    /// size.
+    ///
+    /// With a `timing` file from `script --timing`, in either the classic or
+    /// the advanced format, the output keeps its original pace. Without one
+    /// the whole output is shown at once, split into lines for stepping.
+    pub fn from_typescript(
+        bytes: &[u8],
+        timing: Option<&str>,
+        rows: u16,
+        cols: u16,
+    ) -> anyhow::Result<Self> {
+        let mut bytes = bytes;
+        let (mut rows, mut cols) = (rows, cols);
+
+        if bytes.starts_with(b"Script started on ") {
+            let end = bytes
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(bytes.len(), |i| i + 1);
+            let header = String::from_utf8_lossy(&bytes[..end]);
+            rows = header_size(&header, "LINES").unwrap_or(rows);
+            cols = header_size(&header, "COLUMNS").unwrap_or(cols);
+            bytes = &bytes[end..];
+        }
+        if let Some(start) = find_last(bytes, b"\nScript done on ") {
+            bytes = &bytes[..start + 1];
+        }
+
+        let started = Instant::now();
+        let mut events = Vec::new();
+        match timing {
+            Some(timing) => {
+                let mut time = started;
+                let mut offset = 0;
+                for line in timing.lines() {
+                    let fields: Vec<_> = line.split_whitespace().collect();
+                    let (delay, size) = match fields[..] {
+                        [delay, size] => (delay, size),
+                        ["O", delay, size, ..] => (delay, size),
+                        // Input, signals and headers of the advanced format
+                        [_, delay, ..] => {
+                            time += Duration::from_secs_f64(delay.parse::<f64>()?.max(0.0));
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    time += Duration::from_secs_f64(delay.parse::<f64>()?.max(0.0));
+                    let end = (offset + size.parse::<usize>()?).min(bytes.len());
+                    events.push(TerminalEvent::Output {
+                        bytes: bytes[offset..end].to_vec(),
+                        time,
+                    });
+                    offset = end;
+                }
+                if offset < bytes.len() {
+                    events.push(TerminalEvent::Output {
+                        bytes: bytes[offset..].to_vec(),
+                        time,
+                    });
+                }
+            }
+            None => {
+                for line in bytes.split_inclusive(|&b| b == b'\n') {
+                    events.push(TerminalEvent::Output {
+                        bytes: line.to_vec(),
+                        time: started,
+                    });
+                }
+            }
+        }
+
+        Ok(Recording::new(rows, cols, started, events))
+    }
+
+    /// Offset of `event` from the start of the recording.
+    pub fn offset(&self, event: &TerminalEvent) -> Duration {
+        event.time().saturating_duration_since(self.started)
+    }
+
+    /// Offset of the last event.
+    pub fn duration(&self) -> Duration {
+        self.events
+            .last()
+            .map_or(Duration::ZERO, |event| self.offset(event))
+    }
+}
+
+/// Reads `NAME="N"` from a `script(1)` header line.
+fn header_size(header: &str, name: &str) -> Option<u16> {
+    let start = header.find(&format!("{}=\"", name))? + name.len() + 2;
+    let len = header[start..].find('"')?;
+    header[start..start + len].parse().ok()
+}
+
+fn find_last(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+/// Plays a [`Recording`] back through a virtual terminal screen and a
+/// renderer, with pausing, stepping, seeking and variable speed.
+pub struct Player {
+    recording: Recording,
+    title: String,
+    parser: Parser,
+    /// Index of the next event to apply.
+    next: usize,
+    /// Playback position within the recording.
+    clock: Duration,
+    speed: f64,
+    paused: bool,
+    /// Most screen rows to show, following the bottom of the output.
+    max_height: Option<u16>,
+    renderer: Box<dyn Renderer>,
+}
+
+impl Player {
+    /// Plays `recording` inline on stdout, with `title` in the status line.
+    pub fn new(recording: Recording, title: impl Into<String>) -> Self {
+        Self::with_renderer(recording, title, Box::new(InlineRenderer::new()))
+    }
+
+    pub fn with_renderer(
+        recording: Recording,
+        title: impl Into<String>,
+        renderer: Box<dyn Renderer>,
+    ) -> Self {
+        let parser = Parser::new(recording.rows, recording.cols, 0);
+        Player {
+            recording,
+            title: title.into(),
+            parser,
+            next: 0,
+            clock: Duration::ZERO,
+            speed: 1.0,
+            paused: false,
+            max_height: None,
+            renderer,
+        }
+    }
+
+    pub fn position(&self) -> Duration {
+        self.clock.min(self.recording.duration())
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Sets the playback speed, limited to a sensible range. NaN leaves it
+    /// unchanged.
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed.is_nan() {
+            return;
+        }
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Shows at most `rows` rows of the recorded screen, leaving out rows
+    /// at the top, e.g. to fit a recording made on a taller terminal.
+    pub fn set_max_height(&mut self, rows: Option<u16>) {
+        self.max_height = rows;
+    }
+
+    /// Returns true once every event has been played.
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.recording.events.len()
+    }
+
+    /// Moves playback forward by `elapsed` real time, scaled by the speed.
+    pub fn advance(&mut self, elapsed: Duration) {
+        if self.paused {
+            return;
+        }
+        self.clock += elapsed.mul_f64(self.speed);
+        self.play_until(self.clock);
+    }
+
+    /// Real time until the next event is due, or `None` when paused or
+    /// finished.
+    pub fn until_next_event(&self) -> Option<Duration> {
+        if self.paused {
+            return None;
+        }
+        let time = self.next_offset()?;
+        Some(time.saturating_sub(self.clock).div_f64(self.speed))
+    }
+
+    /// Plays the next event right away.
+    pub fn step(&mut self) {
+        if let Some(time) = self.next_offset() {
+            self.clock = self.clock.max(time);
+            self.play_until(self.clock);
+        }
+    }
+
+    /// Jumps to `position`, replaying the recording from the start when
+    /// moving backwards.
+    pub fn seek(&mut self, position: Duration) {
+        if position < self.clock {
+            self.parser = Parser::new(self.recording.rows, self.recording.cols, 0);
+            self.next = 0;
+        }
+        self.clock = position;
+        self.play_until(position);
+    }
+
+    /// Offset of the next event to apply.
+    fn next_offset(&self) -> Option<Duration> {
+        let event = self.recording.events.get(self.next)?;
+        Some(self.recording.offset(event))
+    }
+
+    fn play_until(&mut self, position: Duration) {
+        while let Some(time) = self.next_offset() {
+            if time > position {
+                break;
+            }
+            match &self.recording.events[self.next] {
+                TerminalEvent::Output { bytes, .. } => self.parser.process(bytes),
+                TerminalEvent::Resize { rows, cols, .. } => {
+                    self.parser.screen_mut().set_size(*rows, *cols)
+                }
+                _ => {}
+            }
+            self.next += 1;
+        }
+    }
+
+    /// Handles the playback keys: space pauses, `.` steps, the arrow keys
+    /// seek, `+`/`-` change the speed and `q` quits.
+    pub fn handle_event(&mut self, event: &Event) -> Outcome {
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind,
+            ..
+        }) = event
+        else {
+            return Outcome::Continue;
+        };
+        if *kind == KeyEventKind::Release {
+            return Outcome::Continue;
+        }
+
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                return Outcome::Exit;
+            }
+            KeyCode::Char('q') | KeyCode::Esc => return Outcome::Exit,
+            KeyCode::Char(' ' | 'p') => self.paused = !self.paused,
+            KeyCode::Char('.') => {
+                self.paused = true;
+                self.step();
+            }
+            KeyCode::Right => self.seek(self.position() + SEEK_STEP),
+            KeyCode::Left => self.seek(self.position().saturating_sub(SEEK_STEP)),
+            KeyCode::Home | KeyCode::Char('0') => self.seek(Duration::ZERO),
+            KeyCode::Char('+' | '=') => self.set_speed(self.speed * 2.0),
+            KeyCode::Char('-') => self.set_speed(self.speed / 2.0),
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+
+    /// Draws the screen at the current position with a status line below.
+    pub fn render(&mut self) -> anyhow::Result<()> {
+        let status = self.status_line();
+        let screen = self.parser.screen();
+        let (top, rows) = shown_window(screen, None, 0, self.max_height);
+        self.renderer.render(&[Frame {
+            screen,
+            top,
+            rows,
+            header: None,
+            footer: Some(&status),
+            cursor: None,
+            fullscreen: false,
+            mouse: MouseProtocolMode::None,
+            hyperlinks: &[],
+        }])
+    }
+
+    /// Erases the panel from the real terminal.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.renderer.clear()
+    }
+
+    fn status_line(&self) -> StatusLine {
+        let icon = if self.paused { "⏸" } else { "▶" };
+        StatusLine {
+            left: format!(" {} {}", icon, self.title),
+            right: format!(
+                "{}x  {} / {} ",
+                self.speed,
+                format_elapsed(self.position()),
+                format_elapsed(self.recording.duration())
+            ),
+            style: Style {
+                fg: Color::Idx(15),
+                bg: Color::Idx(5),
+                bold: true,
+                ..Style::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Recorder;
+    use std::io::Write;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer whose contents can be read after it was moved into a
+    /// recorder.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn output(recording: &Recording) -> Vec<u8> {
+        recording
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                TerminalEvent::Output { bytes, .. } => Some(bytes.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn asciicast_round_trips_through_the_recorder() {
+        let buffer = SharedBuffer::default();
+        let (sender, receiver) = channel();
+        let started = Instant::now();
+        let recorder = Recorder::start(buffer.clone(), 24, 80, "echo \"hi\"", receiver).unwrap();
+
+        let text = "\x1b[1mbold\x1b[0m \"quoted\" back\\slash\ttab\r\nwide 字 emoji 😀\r\n";
+        let (first, rest) = text.as_bytes().split_at(text.find('字').unwrap() + 1);
+        // Recorded at the time the events carry, not when they arrive
+        let events = [
+            TerminalEvent::Output {
+                bytes: first.to_vec(),
+                time: started,
+            },
+            TerminalEvent::Resize {
+                rows: 30,
+                cols: 100,
+                time: started + Duration::from_secs(1),
+            },
+            TerminalEvent::Output {
+                bytes: rest.to_vec(),
+                time: started + Duration::from_millis(2500),
+            },
+        ];
+        for event in events {
+            sender.send(event).unwrap();
+        }
+        drop(sender);
+        recorder.finish().unwrap();
+
+        let cast = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let recording = Recording::from_asciicast(&cast).unwrap();
+        assert_eq!((recording.rows, recording.cols), (24, 80));
+        assert_eq!(output(&recording), text.as_bytes());
+        assert!(matches!(
+            recording.events[1],
+            TerminalEvent::Resize {
+                rows: 30,
+                cols: 100,
+                ..
+            }
+        ));
+        let offsets: Vec<_> = recording
+            .events
+            .iter()
+            .map(|event| recording.offset(event).as_secs_f64())
+            .collect();
+        for (offset, expected) in offsets.iter().zip([0.0, 1.0, 2.5]) {
+            assert!((offset - expected).abs() < 0.1, "{:?}", offsets);
+        }
+    }
+
+    #[test]
+    fn parses_asciicast_escapes() {
+        let cast = concat!(
+            "{\"version\": 2, \"width\": 10, \"height\": 3}\n",
+            "[0.5, \"o\", \"\\u001b[0m\\ud83d\\ude00 \\\"\\\\\\/\\n\"]\n",
+            "[1.25, \"i\", \"typed\"]\n",
+            "[2, \"r\", \"20x5\"]\n",
+        );
+        let recording = Recording::from_asciicast(cast).unwrap();
+        assert_eq!((recording.rows, recording.cols), (3, 10));
+        assert_eq!(output(&recording), "\x1b[0m😀 \"\\/\n".as_bytes());
+        assert_eq!(recording.events.len(), 2);
+        assert_eq!(
+            recording.offset(&recording.events[0]),
+            Duration::from_millis(500)
+        );
+        assert!(matches!(
+            recording.events[1],
+            TerminalEvent::Resize {
+                rows: 5,
+                cols: 20,
+                ..
+            }
+        ));
+        assert_eq!(recording.duration(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn rejects_malformed_asciicasts() {
+        let error = |cast: &str| Recording::from_asciicast(cast).err().unwrap().to_string();
+        assert_eq!(
+            error("{\"version\": 1, \"width\": 80, \"height\": 24}"),
+            "unsupported asciicast version 1"
+        );
+        assert_eq!(error("not json"), "malformed asciicast header");
+        let header = "{\"version\": 2, \"width\": 80, \"height\": 24}\n";
+        assert_eq!(
+            error(&format!("{}[0.1, \"o\", \"ok\"]\n[0.2, \"o\"\n", header)),
+            "malformed event on line 3"
+        );
+        assert_eq!(
+            error(&format!("{}[0.1, \"o\", 5]\n", header)),
+            "malformed event on line 2"
+        );
+        assert_eq!(
+            error(&format!("{}[0.1, \"r\", \"wide\"]\n", header)),
+            "malformed resize on line 2"
+        );
+        assert_eq!(
+            error(&format!("{}[0.1, \"o\", \"\\ud83d\"]\n", header)),
+            "malformed event on line 2"
+        );
+    }
+
+    #[test]
+    fn parses_typescripts_with_timing() {
+        let typescript = b"Script started on 2024-01-01 [COLUMNS=\"100\" LINES=\"30\"]\n\
+                           one\ntwo\n\nScript done on 2024-01-01 [COMMAND_EXIT_CODE=\"0\"]\n";
+        let classic =
+            Recording::from_typescript(typescript, Some("0.5 4\n0.25 5\n"), 24, 80).unwrap();
+        let advanced = Recording::from_typescript(
+            typescript,
+            Some("H 0 START_TIME 2024\nO 0.5 4\nI 0.1 1\nO 0.15 5\n"),
+            24,
+            80,
+        )
+        .unwrap();
+
+        for recording in [classic, advanced] {
+            assert_eq!((recording.rows, recording.cols), (30, 100));
+            assert_eq!(output(&recording), b"one\ntwo\n\n");
+            let times: Vec<_> = recording
+                .events
+                .iter()
+                .map(|event| recording.offset(event))
+                .collect();
+            assert_eq!(
+                times,
+                [Duration::from_millis(500), Duration::from_millis(750)]
+            );
+        }
+    }
+
+    #[test]
+    fn splits_typescripts_without_timing_into_lines() {
+        let recording = Recording::from_typescript(b"one\ntwo\nthree", None, 24, 80).unwrap();
+        assert_eq!((recording.rows, recording.cols), (24, 80));
+        assert_eq!(recording.events.len(), 3);
+        assert_eq!(output(&recording), b"one\ntwo\nthree");
+        assert_eq!(recording.duration(), Duration::ZERO);
+    }
+
+    /// Records the rows of the recorded screen each frame showed.
+    struct WindowRenderer(Arc<Mutex<Vec<(u16, u16)>>>);
+
+    impl Renderer for WindowRenderer {
+        fn render(&mut self, frames: &[Frame<'_>]) -> anyhow::Result<()> {
+            let mut windows = self.0.lock().unwrap();
+            windows.extend(frames.iter().map(|frame| (frame.top, frame.rows)));
+            Ok(())
+        }
+
+        fn clear(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn print_above(&mut self, _text: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn max_height_shows_the_bottom_of_a_taller_recording() {
+        let text: String = (0..50).map(|line| format!("line {line}\r\n")).collect();
+        let started = Instant::now();
+        let events = vec![TerminalEvent::Output {
+            bytes: text.into_bytes(),
+            time: started,
+        }];
+        let windows = Arc::new(Mutex::new(Vec::new()));
+        let mut player = Player::with_renderer(
+            Recording::new(60, 80, started, events),
+            "tall",
+            Box::new(WindowRenderer(windows.clone())),
+        );
+        player.advance(Duration::ZERO);
+
+        player.render().unwrap();
+        player.set_max_height(Some(20));
+        player.render().unwrap();
+        player.set_max_height(Some(100));
+        player.render().unwrap();
+
+        // Blank rows below the last line are left out either way
+        assert_eq!(*windows.lock().unwrap(), [(0, 50), (30, 20), (0, 50)]);
+    }
+}
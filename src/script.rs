@@ -0,0 +1,427 @@
+use crate::input::{encode_key, parse_key};
+use crate::snapshot::SnapshotFormat;
+use crate::virtual_terminal::{exit_code, VirtualTerminal};
+use clap::ValueEnum;
+use crossterm::event::{Event, KeyEvent};
+use regex::Regex;
+use signal_hook::consts::SIGKILL;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use vt100::Screen;
+
+/// How long `expect` and `wait-exit` wait unless the script sets a timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which the screen is checked while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A list of steps that drive a command through its virtual terminal, like
+/// expect(1) but matching against the screen instead of the raw output.
+///
+/// Each line holds one step, split into words like a shell would. Blank
+/// lines and lines starting with `#` are ignored:
+///
+/// - `spawn COMMAND [ARG]...` starts the command, killing the previous one
+/// - `timeout DURATION` sets how long the following steps wait
+/// - `expect TEXT` waits until the screen shows `TEXT`
+/// - `expect-regex PATTERN` waits until a screen line matches `PATTERN`, a
+///   [`regex`] pattern
+/// - `send TEXT` types `TEXT`, with `\r`, `\n`, `\t`, `\e`, `\xHH` and `\\`
+///   escapes
+/// - `key NAME...` presses keys like `Enter`, `Up`, `F2` or `Ctrl-c`
+/// - `sleep DURATION` pauses, e.g. before pressing a key
+/// - `wait-exit [CODE]` waits for the command to exit, with `CODE` if given
+/// - `assert TEXT`, `assert-not TEXT` and `assert-regex PATTERN` check the
+///   screen right away
+/// - `snapshot [text|ansi|json]` prints the screen
+///
+/// Durations are milliseconds, seconds or minutes, like `500ms`, `2s` or
+/// `1m`.
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+struct Step {
+    line: usize,
+    action: Action,
+}
+
+enum Action {
+    Spawn(Vec<String>),
+    Timeout(Duration),
+    Expect(Matcher),
+    Send(Vec<u8>),
+    Keys(Vec<KeyEvent>),
+    Sleep(Duration),
+    WaitExit(Option<i32>),
+    Assert(Matcher, bool),
+    Snapshot(SnapshotFormat),
+}
+
+enum Matcher {
+    Text(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn matches(&self, screen: &Screen) -> bool {
+        let contents = screen.contents();
+        match self {
+            Matcher::Text(text) => contents.contains(text.as_str()),
+            Matcher::Regex(regex) => contents.lines().any(|line| regex.is_match(line)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Matcher::Text(text) => format!("text {:?}", text),
+            Matcher::Regex(regex) => format!("regex {:?}", regex.as_str()),
+        }
+    }
+}
+
+impl Script {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut steps = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let action = parse_action(trimmed)
+                .map_err(|error| anyhow::anyhow!("line {}: {}", line_number, error))?;
+            steps.push(Step {
+                line: line_number,
+                action,
+            });
+        }
+        Ok(Script { steps })
+    }
+
+    /// Runs the steps, starting commands with `spawn` and writing snapshots
+    /// to `out`. Fails at the first step that doesn't succeed, with the
+    /// screen at that point in the error. A command still running at the
+    /// end is killed.
+    pub fn run(
+        &self,
+        mut spawn: impl FnMut(&[String]) -> anyhow::Result<VirtualTerminal>,
+        out: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        let mut runner = Runner {
+            vt: None,
+            timeout: DEFAULT_TIMEOUT,
+        };
+        let result = self.steps.iter().try_for_each(|step| {
+            runner
+                .step(&step.action, &mut spawn, out)
+                .map_err(|error| runner.failure(step.line, error))
+        });
+        runner.stop()?;
+        result
+    }
+}
+
+struct Runner {
+    vt: Option<VirtualTerminal>,
+    timeout: Duration,
+}
+
+impl Runner {
+    fn step(
+        &mut self,
+        action: &Action,
+        spawn: &mut dyn FnMut(&[String]) -> anyhow::Result<VirtualTerminal>,
+        out: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        match action {
+            Action::Spawn(command) => {
+                self.stop()?;
+                self.vt = Some(spawn(command)?);
+            }
+            Action::Timeout(timeout) => self.timeout = *timeout,
+            Action::Expect(matcher) => {
+                let vt = self.vt()?;
+                let deadline = Instant::now() + self.timeout;
+                loop {
+                    // Checked before matching, so output that arrived just
+                    // before the end is still looked at
+                    let finished = vt.is_finished();
+                    if vt.with_screen(|screen| matcher.matches(screen)) {
+                        break;
+                    }
+                    if finished {
+                        anyhow::bail!("the command exited without showing {}", matcher.describe());
+                    }
+                    if Instant::now() >= deadline {
+                        anyhow::bail!(
+                            "timed out after {:?} waiting for {}",
+                            self.timeout,
+                            matcher.describe()
+                        );
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+            }
+            Action::Send(bytes) => self.vt()?.input().write_bytes(bytes)?,
+            Action::Keys(keys) => {
+                let input = self.vt()?.input();
+                for key in keys {
+                    input.send_event(&Event::Key(*key))?;
+                }
+            }
+            Action::Sleep(duration) => std::thread::sleep(*duration),
+            Action::WaitExit(expected) => {
+                let vt = self.vt()?;
+                let deadline = Instant::now() + self.timeout;
+                let Some(status) = vt.wait_timeout(self.timeout) else {
+                    anyhow::bail!("timed out after {:?} waiting for exit", self.timeout);
+                };
+                // Let the last of the output reach the screen for later asserts
+                while !vt.is_finished() && Instant::now() < deadline {
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                let code = exit_code(&status);
+                if let Some(expected) = expected
+                    && code != *expected
+                {
+                    anyhow::bail!("exited with {}, expected {}", code, expected);
+                }
+            }
+            Action::Assert(matcher, expected) => {
+                let found = self.vt()?.with_screen(|screen| matcher.matches(screen));
+                if found != *expected {
+                    let negation = if *expected { "does not show" } else { "shows" };
+                    anyhow::bail!("the screen {} {}", negation, matcher.describe());
+                }
+            }
+            Action::Snapshot(format) => {
+                out.write_all(self.vt()?.snapshot(*format).as_bytes())?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn vt(&self) -> anyhow::Result<&VirtualTerminal> {
+        match &self.vt {
+            Some(vt) => Ok(vt),
+            None => anyhow::bail!("no command running, `spawn` one first"),
+        }
+    }
+
+    /// Adds the line of the failed step and the screen to `error`.
+    fn failure(&self, line: usize, error: anyhow::Error) -> anyhow::Error {
+        let screen = match &self.vt {
+            Some(vt) => format!("\n\nScreen:\n{}", vt.snapshot(SnapshotFormat::Text)),
+            None => String::new(),
+        };
+        anyhow::anyhow!("line {}: {}{}", line, error, screen)
+    }
+
+    /// Kills the current command if it is still running.
+    fn stop(&mut self) -> anyhow::Result<()> {
+        if let Some(vt) = self.vt.take()
+            && vt.exit_status().is_none()
+        {
+            vt.signal(SIGKILL)?;
+            vt.wait();
+        }
+        Ok(())
+    }
+}
+
+fn parse_action(line: &str) -> anyhow::Result<Action> {
+    let words = shell_words::split(line)?;
+    let Some((name, args)) = words.split_first() else {
+        anyhow::bail!("empty step");
+    };
+    let text = args.join(" ");
+
+    Ok(match name.as_str() {
+        "spawn" if !args.is_empty() => Action::Spawn(args.to_vec()),
+        "timeout" => Action::Timeout(parse_duration(&text)?),
+        "expect" => Action::Expect(Matcher::Text(text)),
+        "expect-regex" => Action::Expect(Matcher::Regex(Regex::new(&text)?)),
+        "send" => Action::Send(unescape(&text)?),
+        "key" if !args.is_empty() => Action::Keys(
+            args.iter()
+                .map(|name| match parse_key(name) {
+                    Some(key) if encode_key(&key, false).is_some() => Ok(key),
+                    _ => anyhow::bail!("unknown key `{}`", name),
+                })
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        "sleep" => Action::Sleep(parse_duration(&text)?),
+        "wait-exit" => match args {
+            [] => Action::WaitExit(None),
+            [code] => Action::WaitExit(Some(code.parse()?)),
+            _ => anyhow::bail!("`wait-exit` takes at most one exit code"),
+        },
+        "assert" => Action::Assert(Matcher::Text(text), true),
+        "assert-not" => Action::Assert(Matcher::Text(text), false),
+        "assert-regex" => Action::Assert(Matcher::Regex(Regex::new(&text)?), true),
+        "snapshot" => Action::Snapshot(match args {
+            [] => SnapshotFormat::Text,
+            [format] => SnapshotFormat::from_str(format, true).map_err(anyhow::Error::msg)?,
+            _ => anyhow::bail!("`snapshot` takes at most one format"),
+        }),
+        "spawn" | "key" => anyhow::bail!("`{}` needs an argument", name),
+        name => anyhow::bail!("unknown step `{}`", name),
+    })
+}
+
+/// Parses durations like `500ms`, `2s`, `1.5s` or `1m`. A bare number is in
+/// seconds.
+fn parse_duration(text: &str) -> anyhow::Result<Duration> {
+    let (number, scale) = if let Some(number) = text.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = text.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = text.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (text, 1.0)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(value) if value >= 0.0 && value.is_finite() => {
+            Ok(Duration::from_secs_f64(value * scale))
+        }
+        _ => anyhow::bail!("invalid duration `{}`", text),
+    }
+}
+
+/// Replaces the C-style escapes in `text` with the bytes they stand for.
+fn unescape(text: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('e') => bytes.push(0x1b),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 => bytes.push(byte),
+                    _ => anyhow::bail!("invalid escape `\\x{}`", hex),
+                }
+            }
+            Some(c) => anyhow::bail!("unknown escape `\\{}`", c),
+            None => anyhow::bail!("trailing `\\`"),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::{KeyCode, KeyModifiers};
+    use vt100::Parser;
+
+    fn action(line: &str) -> Action {
+        parse_action(line).unwrap()
+    }
+
+    fn error(line: &str) -> String {
+        parse_action(line).err().unwrap().to_string()
+    }
+
+    fn screen(output: &str) -> Parser {
+        let mut parser = Parser::new(4, 20, 0);
+        parser.process(output.as_bytes());
+        parser
+    }
+
+    #[test]
+    fn parses_steps() {
+        assert!(matches!(action("spawn ls -l"), Action::Spawn(command) if command == ["ls", "-l"]));
+        assert!(
+            matches!(action("timeout 1.5s"), Action::Timeout(t) if t == Duration::from_millis(1500))
+        );
+        assert!(
+            matches!(action("expect 'a  b'"), Action::Expect(Matcher::Text(text)) if text == "a  b")
+        );
+        assert!(matches!(action("send \"y\\r\""), Action::Send(bytes) if bytes == b"y\r"));
+        assert!(
+            matches!(action("sleep 200ms"), Action::Sleep(t) if t == Duration::from_millis(200))
+        );
+        assert!(matches!(action("wait-exit"), Action::WaitExit(None)));
+        assert!(matches!(action("wait-exit 3"), Action::WaitExit(Some(3))));
+        assert!(matches!(
+            action("assert-not error"),
+            Action::Assert(_, false)
+        ));
+        assert!(matches!(
+            action("snapshot json"),
+            Action::Snapshot(SnapshotFormat::Json)
+        ));
+        assert!(matches!(
+            action("snapshot"),
+            Action::Snapshot(SnapshotFormat::Text)
+        ));
+    }
+
+    #[test]
+    fn parses_keys() {
+        let Action::Keys(keys) = action("key Down Ctrl-c") else {
+            panic!("not a key step");
+        };
+        assert_eq!(
+            keys,
+            [
+                KeyEvent::new(KeyCode::Down, KeyModifiers::NONE),
+                KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_steps() {
+        assert_eq!(error("launch ls"), "unknown step `launch`");
+        assert_eq!(error("spawn"), "`spawn` needs an argument");
+        assert_eq!(error("key Enter Nope"), "unknown key `Nope`");
+        assert_eq!(error("timeout soon"), "invalid duration `soon`");
+        assert_eq!(error("send '\\q'"), "unknown escape `\\q`");
+        assert!(error("expect-regex 'a{2'").contains("unclosed counted repetition"));
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_step() {
+        let error = Script::parse("# setup\n\nspawn ls\nbogus\n").err().unwrap();
+        assert_eq!(error.to_string(), "line 4: unknown step `bogus`");
+    }
+
+    #[test]
+    fn unescapes_text() {
+        assert_eq!(
+            unescape("a\\tb\\e[A\\x7f\\\\").unwrap(),
+            b"a\tb\x1b[A\x7f\\"
+        );
+        assert!(unescape("\\x4").is_err());
+        assert!(unescape("trailing\\").is_err());
+    }
+
+    #[test]
+    fn matches_text_and_regexes_against_the_screen() {
+        let parser = screen("Progress: 42%\r\nDone");
+        let screen = parser.screen();
+        let regex = |pattern| Matcher::Regex(Regex::new(pattern).unwrap());
+
+        assert!(Matcher::Text("42%".to_string()).matches(screen));
+        assert!(!Matcher::Text("43%".to_string()).matches(screen));
+        assert!(regex(r"^Progress: \d+%$").matches(screen));
+        assert!(regex("^Done$").matches(screen));
+        // Each line is matched on its own
+        assert!(!regex("42%.*Done").matches(screen));
+    }
+}
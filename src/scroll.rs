@@ -0,0 +1,212 @@
+use crate::render::{walk_scrollback, StatusLine, Style};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseEventKind};
+use vt100::Screen;
+
+/// Rows moved per mouse wheel notch.
+const WHEEL_ROWS: usize = 3;
+
+/// Returns true for the key that toggles scroll mode, Ctrl-].
+pub(crate) fn is_toggle_key(event: &Event) -> bool {
+    // Legacy terminals report Ctrl-] as Ctrl-5
+    matches!(
+        event,
+        Event::Key(KeyEvent {
+            code: KeyCode::Char(']' | '5'),
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) if modifiers.contains(KeyModifiers::CONTROL)
+    )
+}
+
+/// Returns true for keys that enter scroll mode and scroll up right away,
+/// like Shift+PageUp in most terminals.
+pub(crate) fn is_enter_key(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Key(KeyEvent {
+            code: KeyCode::PageUp,
+            modifiers: KeyModifiers::SHIFT,
+            kind: KeyEventKind::Press,
+            ..
+        })
+    )
+}
+
+/// What the caller should do after an event was handled.
+pub enum Outcome {
+    Continue,
+    Exit,
+}
+
+/// Browsing state while the panel is scrolled back through the history.
+///
+/// The child keeps running meanwhile; vt100 keeps the scrolled view anchored
+/// as new output pushes lines into the scrollback.
+#[derive(Default)]
+pub(crate) struct ScrollMode {
+    search: Option<String>,
+    prompt: Option<String>,
+    message: Option<String>,
+}
+
+impl ScrollMode {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &Event, screen: &mut Screen) -> Outcome {
+        if is_toggle_key(event) {
+            return Outcome::Exit;
+        }
+
+        match event {
+            Event::Key(key) if key.kind != KeyEventKind::Release => {
+                if self.prompt.is_some() {
+                    self.handle_prompt_key(key, screen);
+                    return Outcome::Continue;
+                }
+                return self.handle_key(key, screen);
+            }
+            Event::Paste(text) => {
+                if let Some(prompt) = &mut self.prompt {
+                    prompt.push_str(text);
+                }
+            }
+            Event::Mouse(mouse) => match mouse.kind {
+                MouseEventKind::ScrollUp => scroll_up(screen, WHEEL_ROWS),
+                MouseEventKind::ScrollDown => scroll_down(screen, WHEEL_ROWS),
+                _ => {}
+            },
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+
+    fn handle_key(&mut self, key: &KeyEvent, screen: &mut Screen) -> Outcome {
+        let (rows, _) = screen.size();
+        let page = (rows as usize).saturating_sub(1).max(1);
+        self.message = None;
+
+        match key.code {
+            KeyCode::PageUp | KeyCode::Char('b') => scroll_up(screen, page),
+            KeyCode::PageDown | KeyCode::Char(' ' | 'f') => scroll_down(screen, page),
+            KeyCode::Up | KeyCode::Char('k') => scroll_up(screen, 1),
+            KeyCode::Down | KeyCode::Char('j') => scroll_down(screen, 1),
+            KeyCode::Home | KeyCode::Char('g') => screen.set_scrollback(usize::MAX),
+            KeyCode::End | KeyCode::Char('G') => screen.set_scrollback(0),
+            KeyCode::Char('/') => self.prompt = Some(String::new()),
+            KeyCode::Char('n') => self.search(screen, Direction::Older),
+            KeyCode::Char('N') => self.search(screen, Direction::Newer),
+            KeyCode::Char('q') | KeyCode::Esc => return Outcome::Exit,
+            _ => {}
+        }
+
+        Outcome::Continue
+    }
+
+    fn handle_prompt_key(&mut self, key: &KeyEvent, screen: &mut Screen) {
+        let Some(prompt) = &mut self.prompt else {
+            return;
+        };
+
+        match key.code {
+            KeyCode::Char(c) => prompt.push(c),
+            KeyCode::Backspace => {
+                prompt.pop();
+            }
+            KeyCode::Enter => {
+                if !prompt.is_empty() {
+                    self.search = Some(prompt.clone());
+                }
+                self.prompt = None;
+                self.search(screen, Direction::Older);
+            }
+            KeyCode::Esc => self.prompt = None,
+            _ => {}
+        }
+    }
+
+    fn search(&mut self, screen: &mut Screen, direction: Direction) {
+        let Some(query) = &self.search else {
+            return;
+        };
+
+        let (lines, scrollback_len) = history_text(screen);
+        let top = scrollback_len - screen.scrollback();
+        let found = match direction {
+            Direction::Older => (0..top).rev().find(|&i| lines[i].contains(query.as_str())),
+            Direction::Newer => (top + 1..lines.len()).find(|&i| lines[i].contains(query.as_str())),
+        };
+
+        match found {
+            Some(line) => screen.set_scrollback(scrollback_len.saturating_sub(line)),
+            None => self.message = Some(format!("Pattern not found: {}", query)),
+        }
+    }
+
+    /// Status line shown below the panel while scrolling.
+    pub(crate) fn status(&self, screen: &mut Screen) -> StatusLine {
+        let style = Style {
+            inverse: true,
+            ..Style::default()
+        };
+
+        if let Some(prompt) = &self.prompt {
+            return StatusLine {
+                left: format!("/{}", prompt),
+                right: String::new(),
+                style,
+            };
+        }
+
+        let offset = screen.scrollback();
+        screen.set_scrollback(usize::MAX);
+        let scrollback_len = screen.scrollback();
+        screen.set_scrollback(offset);
+
+        let left = match &self.message {
+            Some(message) => message.clone(),
+            None => "PgUp/PgDn scroll  / search  n/N next/prev  q quit".to_string(),
+        };
+
+        StatusLine {
+            left,
+            right: format!("SCROLL {}/{}", offset, scrollback_len),
+            style,
+        }
+    }
+}
+
+enum Direction {
+    Older,
+    Newer,
+}
+
+fn scroll_up(screen: &mut Screen, rows: usize) {
+    screen.set_scrollback(screen.scrollback().saturating_add(rows));
+}
+
+fn scroll_down(screen: &mut Screen, rows: usize) {
+    screen.set_scrollback(screen.scrollback().saturating_sub(rows));
+}
+
+/// Returns the text of every scrollback line followed by every screen row,
+/// together with the number of scrollback lines.
+fn history_text(screen: &mut Screen) -> (Vec<String>, usize) {
+    let (_, cols) = screen.size();
+    let mut lines = Vec::new();
+
+    walk_scrollback(screen, |screen, chunk| {
+        lines.extend(screen.rows(0, cols).take(chunk as usize));
+    });
+    let scrollback_len = lines.len();
+
+    let offset = screen.scrollback();
+    screen.set_scrollback(0);
+    lines.extend(screen.rows(0, cols));
+    screen.set_scrollback(offset);
+
+    (lines, scrollback_len)
+}
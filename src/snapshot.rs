@@ -0,0 +1,211 @@
+use crate::render::Style;
+use clap::ValueEnum;
+use serde_json::Value;
+use vt100::{Cell, Color, Screen};
+
+/// Output formats for a snapshot of a virtual terminal's screen.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    /// Plain text, one line per row
+    Text,
+    /// Text with SGR sequences for colors and attributes
+    Ansi,
+    /// JSON with the size, the cursor and every cell with its attributes
+    Json,
+}
+
+/// Formats the visible screen, without the scrollback. Text and ANSI
+/// snapshots trim trailing blanks from each row and drop trailing empty rows,
+/// so they compare well in snapshot tests.
+pub fn snapshot(screen: &Screen, format: SnapshotFormat) -> String {
+    match format {
+        SnapshotFormat::Text => text_snapshot(screen),
+        SnapshotFormat::Ansi => ansi_snapshot(screen),
+        SnapshotFormat::Json => json_snapshot(screen),
+    }
+}
+
+fn row_cells(screen: &Screen, row: u16) -> Vec<&Cell> {
+    let (_, cols) = screen.size();
+    (0..cols).filter_map(|col| screen.cell(row, col)).collect()
+}
+
+fn is_blank(cell: &Cell) -> bool {
+    (!cell.has_contents() || cell.contents() == " ") && Style::from_cell(cell) == Style::default()
+}
+
+/// Joins rows into lines, leaving out the empty rows at the bottom.
+fn join_rows(rows: Vec<String>) -> String {
+    let used = rows
+        .iter()
+        .rposition(|row| !row.is_empty())
+        .map_or(0, |row| row + 1);
+    rows[..used]
+        .iter()
+        .map(|row| format!("{}\n", row))
+        .collect()
+}
+
+fn text_snapshot(screen: &Screen) -> String {
+    let (_, cols) = screen.size();
+    let rows = screen
+        .rows(0, cols)
+        .map(|row| row.trim_end().to_string())
+        .collect();
+    join_rows(rows)
+}
+
+fn ansi_snapshot(screen: &Screen) -> String {
+    let (rows, _) = screen.size();
+    let rows = (0..rows)
+        .map(|row| {
+            let cells = row_cells(screen, row);
+            let used = cells
+                .iter()
+                .rposition(|cell| !is_blank(cell))
+                .map_or(0, |col| col + 1);
+
+            let mut line = String::new();
+            let mut pen = Style::default();
+            for cell in cells[..used].iter().filter(|c| !c.is_wide_continuation()) {
+                let style = Style::from_cell(cell);
+                if style != pen {
+                    line.push_str(&style.to_ansi());
+                    pen = style;
+                }
+                line.push_str(if cell.has_contents() {
+                    cell.contents()
+                } else {
+                    " "
+                });
+            }
+            if pen != Style::default() {
+                line.push_str("\x1b[0m");
+            }
+            line
+        })
+        .collect();
+    join_rows(rows)
+}
+
+fn json_snapshot(screen: &Screen) -> String {
+    let (rows, cols) = screen.size();
+    let (cursor_row, cursor_col) = screen.cursor_position();
+
+    let lines: Vec<_> = screen
+        .rows(0, cols)
+        .map(|row| Value::from(row.trim_end()).to_string())
+        .collect();
+    let cells: Vec<_> = (0..rows)
+        .map(|row| {
+            let cells: Vec<_> = row_cells(screen, row).into_iter().map(json_cell).collect();
+            format!("[{}]", cells.join(","))
+        })
+        .collect();
+
+    format!(
+        "{{\"rows\": {}, \"cols\": {}, \"cursor\": {{\"row\": {}, \"col\": {}, \"visible\": {}}}, \
+         \"alternate_screen\": {}, \"lines\": [{}], \"cells\": [{}]}}\n",
+        rows,
+        cols,
+        cursor_row,
+        cursor_col,
+        !screen.hide_cursor(),
+        screen.alternate_screen(),
+        lines.join(", "),
+        cells.join(", ")
+    )
+}
+
+fn json_cell(cell: &Cell) -> String {
+    format!(
+        "{{\"contents\":{},\"fg\":{},\"bg\":{},\"bold\":{},\"dim\":{},\"italic\":{},\
+         \"underline\":{},\"inverse\":{},\"wide\":{}}}",
+        Value::from(cell.contents()),
+        json_color(cell.fgcolor()),
+        json_color(cell.bgcolor()),
+        cell.bold(),
+        cell.dim(),
+        cell.italic(),
+        cell.underline(),
+        cell.inverse(),
+        cell.is_wide()
+    )
+}
+
+/// Default colors are `null`, palette colors their index and true colors
+/// `"#rrggbb"`.
+fn json_color(color: Color) -> String {
+    match color {
+        Color::Default => "null".to_string(),
+        Color::Idx(index) => index.to_string(),
+        Color::Rgb(r, g, b) => format!("\"#{:02x}{:02x}{:02x}\"", r, g, b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vt100::Parser;
+
+    fn screen(output: &str) -> Parser {
+        let mut parser = Parser::new(4, 20, 0);
+        parser.process(output.as_bytes());
+        parser
+    }
+
+    #[test]
+    fn text_snapshot_trims_blanks_and_empty_rows() {
+        let parser = screen("one   \r\n\r\n  \x1b[41mtwo\x1b[0m   ");
+        assert_eq!(
+            snapshot(parser.screen(), SnapshotFormat::Text),
+            "one\n\n  two\n"
+        );
+        assert_eq!(snapshot(screen("").screen(), SnapshotFormat::Text), "");
+    }
+
+    #[test]
+    fn ansi_snapshot_emits_sgr_on_changes_and_resets_at_the_end() {
+        let parser =
+            screen("\x1b[1;31mred\x1b[0m plain \x1b[44m  \x1b[0m\r\n\x1b[38;2;255;128;0mrgb");
+        assert_eq!(
+            snapshot(parser.screen(), SnapshotFormat::Ansi),
+            "\x1b[0;1;38;5;1mred\x1b[0m plain \x1b[0;48;5;4m  \x1b[0m\n\
+             \x1b[0;38;2;255;128;0mrgb\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn ansi_snapshot_skips_wide_continuations() {
+        let parser = screen("世界!");
+        assert_eq!(snapshot(parser.screen(), SnapshotFormat::Ansi), "世界!\n");
+    }
+
+    #[test]
+    fn json_snapshot_encodes_cells_cursor_and_colors() {
+        let parser = screen("\x1b[1;32mok\x1b[0m 世\x1b[38;2;255;128;0m!\x1b[?25l");
+        let json: Value =
+            serde_json::from_str(&snapshot(parser.screen(), SnapshotFormat::Json)).unwrap();
+
+        assert_eq!(json["rows"], 4);
+        assert_eq!(json["cols"], 20);
+        assert_eq!(
+            json["cursor"],
+            serde_json::json!({"row": 0, "col": 6, "visible": false})
+        );
+        assert_eq!(json["alternate_screen"], false);
+        assert_eq!(json["lines"], serde_json::json!(["ok 世!", "", "", ""]));
+
+        let row = &json["cells"][0];
+        assert_eq!(row.as_array().unwrap().len(), 20);
+        assert_eq!(row[0]["contents"], "o");
+        assert_eq!(row[0]["fg"], 2);
+        assert_eq!(row[0]["bold"], true);
+        assert_eq!(row[2]["fg"], Value::Null);
+        assert_eq!(row[2]["bold"], false);
+        assert_eq!(row[3]["contents"], "世");
+        assert_eq!(row[3]["wide"], true);
+        assert_eq!(row[4]["contents"], "");
+        assert_eq!(row[5]["fg"], "#ff8000");
+    }
+}
@@ -0,0 +1,271 @@
+use crate::render::{Frame, InlineRenderer, Renderer};
+use crate::virtual_terminal::{hyperlinks, VirtualTerminal};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use portable_pty::ExitStatus;
+
+/// Several virtual terminals drawn as panels stacked on top of each other,
+/// one of which receives keyboard input.
+pub struct PanelStack {
+    panels: Vec<VirtualTerminal>,
+    focus: usize,
+    renderer: Box<dyn Renderer>,
+    /// Panel whose finished lines were printed last.
+    last_printed: Option<usize>,
+}
+
+/// Returns the panel index selected by Alt+1 to Alt+9.
+fn focus_key(event: &Event) -> Option<usize> {
+    match event {
+        Event::Key(KeyEvent {
+            code: KeyCode::Char(c @ '1'..='9'),
+            modifiers: KeyModifiers::ALT,
+            kind: KeyEventKind::Press,
+            ..
+        }) => Some(*c as usize - '1' as usize),
+        _ => None,
+    }
+}
+
+impl PanelStack {
+    /// Stacks `panels` top to bottom, drawing them inline on stdout.
+    pub fn new(panels: Vec<VirtualTerminal>) -> Self {
+        Self::with_renderer(panels, Box::new(InlineRenderer::new()))
+    }
+
+    /// Stacks `panels` top to bottom, drawing them with `renderer`. The
+    /// panels' own renderers are dropped, so build them with a
+    /// [`NullRenderer`](crate::NullRenderer).
+    pub fn with_renderer(mut panels: Vec<VirtualTerminal>, renderer: Box<dyn Renderer>) -> Self {
+        for panel in &mut panels {
+            panel.disable_renderer();
+        }
+        let mut stack = PanelStack {
+            panels,
+            focus: 0,
+            renderer,
+            last_printed: None,
+        };
+        stack.focus(0);
+        stack
+    }
+
+    pub fn panels(&self) -> &[VirtualTerminal] {
+        &self.panels
+    }
+
+    pub fn panels_mut(&mut self) -> &mut [VirtualTerminal] {
+        &mut self.panels
+    }
+
+    /// Index of the panel that receives keyboard input.
+    pub fn focused(&self) -> usize {
+        self.focus
+    }
+
+    /// Sends keyboard input to the panel at `index`. Out of range indices are
+    /// ignored.
+    pub fn focus(&mut self, index: usize) {
+        if index >= self.panels.len() {
+            return;
+        }
+        self.focus = index;
+
+        // A single panel behaves exactly like a lone virtual terminal
+        let stacked = self.panels.len() > 1;
+        for (i, panel) in self.panels.iter_mut().enumerate() {
+            panel.set_focused(stacked.then_some(i == index));
+        }
+    }
+
+    /// Switches focus on Alt+1 to Alt+9 and routes every other event to the
+    /// focused panel.
+    pub fn handle_event(&mut self, event: &Event) -> anyhow::Result<()> {
+        if self.panels.len() > 1
+            && let Some(index) = focus_key(event)
+        {
+            self.focus(index);
+            return Ok(());
+        }
+
+        let Some(panel) = self.panels.get_mut(self.focus) else {
+            return Ok(());
+        };
+        if let Event::Mouse(mouse) = event
+            && !panel.is_scrolling()
+        {
+            // Only the focused panel is drawn while it is full screen
+            let shown = match panel.is_fullscreen() {
+                true => 0,
+                false => self.focus,
+            };
+            if let Some((index, row, col)) = self.renderer.locate(mouse.row, mouse.column)
+                && index == shown
+            {
+                panel.input().send_mouse(mouse, row, col)?;
+            }
+            return Ok(());
+        }
+        panel.handle_event(event)
+    }
+
+    /// Returns true once every panel is finished.
+    pub fn is_finished(&self) -> bool {
+        self.panels.iter().all(VirtualTerminal::is_finished)
+    }
+
+    /// Returns every panel to the live view.
+    pub fn exit_scroll_mode(&mut self) -> anyhow::Result<()> {
+        for panel in &mut self.panels {
+            panel.exit_scroll_mode()?;
+        }
+        Ok(())
+    }
+
+    /// Sends `signal` to the process group of every panel.
+    pub fn signal(&self, signal: i32) -> anyhow::Result<()> {
+        for panel in &self.panels {
+            panel.signal(signal)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true once every child has exited, even if some of them still
+    /// have descendants holding their PTY open.
+    pub fn has_exited(&self) -> bool {
+        self.panels
+            .iter()
+            .all(|panel| panel.exit_status().is_some())
+    }
+
+    /// Blocks until every child exits and returns their exit statuses in
+    /// panel order.
+    pub fn wait(&self) -> Vec<ExitStatus> {
+        self.panels.iter().map(VirtualTerminal::wait).collect()
+    }
+
+    /// Draws all panels if any of them changed since the last call,
+    /// returning whether a frame was drawn.
+    pub fn render(&mut self) -> anyhow::Result<bool> {
+        // Only the focused panel may take over the whole real terminal
+        for (i, panel) in self.panels.iter_mut().enumerate() {
+            panel.update_fullscreen(i == self.focus)?;
+        }
+
+        // Every panel has to be asked so none of them keeps a stale dirty flag
+        let changed = self
+            .panels
+            .iter_mut()
+            .fold(false, |changed, panel| panel.take_changed() | changed);
+        if !changed {
+            return Ok(false);
+        }
+
+        let fullscreen = self.panels.iter().position(VirtualTerminal::is_fullscreen);
+        let first = fullscreen.unwrap_or(0);
+        let panels = match fullscreen {
+            Some(index) => &self.panels[index..=index],
+            None => &self.panels[..],
+        };
+
+        let mut parsers: Vec<_> = panels.iter().map(|p| p.lock_parser()).collect();
+        let status_lines: Vec<_> = panels
+            .iter()
+            .zip(&mut parsers)
+            .map(|(panel, parser)| panel.status_lines(parser.screen_mut()))
+            .collect();
+        let cursors: Vec<_> = panels
+            .iter()
+            .zip(&parsers)
+            .map(|(panel, parser)| panel.cursor(parser))
+            .collect();
+        let shown_rows: Vec<_> = panels
+            .iter()
+            .zip(&parsers)
+            .zip(&cursors)
+            .map(|((panel, parser), cursor)| panel.shown_rows(parser.screen(), *cursor))
+            .collect();
+        let links: Vec<_> = parsers
+            .iter_mut()
+            .zip(&shown_rows)
+            .map(|(parser, &(top, rows))| hyperlinks(parser, top..top + rows))
+            .collect();
+        let host_output: String = parsers
+            .iter_mut()
+            .map(|parser| std::mem::take(&mut parser.callbacks_mut().host_output))
+            .collect();
+        self.renderer.passthrough(&host_output)?;
+        let stacked = self.panels.len() > 1;
+        let mut finished_lines = String::new();
+        let panel_parts = panels.iter().zip(&mut parsers).zip(&shown_rows);
+        for (i, ((panel, parser), &(top, _))) in panel_parts.enumerate() {
+            let Some(lines) = panel.finished_lines(parser, top) else {
+                continue;
+            };
+            // Like `tail` with several files, name the panel whenever the
+            // lines switch to another one
+            if stacked && self.last_printed != Some(first + i) {
+                finished_lines.push_str(&format!("==> {} <==\r\n", panel.title()));
+                self.last_printed = Some(first + i);
+            }
+            finished_lines.push_str(&lines);
+        }
+        if !finished_lines.is_empty() {
+            self.renderer.print_above(&finished_lines)?;
+        }
+
+        let frames: Vec<_> = panels
+            .iter()
+            .zip(&parsers)
+            .zip(&status_lines)
+            .zip(cursors.into_iter().zip(&links).zip(shown_rows))
+            .map(
+                |(((panel, parser), (header, footer)), ((cursor, hyperlinks), (top, rows)))| {
+                    Frame {
+                        screen: parser.screen(),
+                        top,
+                        rows,
+                        header: header.as_ref(),
+                        footer: footer.as_ref(),
+                        cursor,
+                        fullscreen: panel.is_fullscreen(),
+                        mouse: panel.mouse_mode(parser.screen()),
+                        hyperlinks,
+                    }
+                },
+            )
+            .collect();
+        self.renderer.render(&frames)?;
+
+        Ok(true)
+    }
+
+    /// Draws every panel again on the next render, e.g. after the real
+    /// terminal was handed to another program.
+    pub fn redraw(&self) {
+        for panel in &self.panels {
+            panel.redraw();
+        }
+    }
+
+    /// Erases all panels from the real terminal.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.renderer.clear()
+    }
+
+    /// Replaces the panels with the complete output history of each
+    /// command, so it ends up in the real terminal's scrollback.
+    pub fn dump_history(&mut self) -> anyhow::Result<()> {
+        let stacked = self.panels.len() > 1;
+        let mut output = String::new();
+
+        for panel in &self.panels {
+            if stacked {
+                output.push_str(&format!("==> {} <==\r\n", panel.title()));
+            }
+            output.push_str(&panel.history());
+        }
+
+        self.renderer.clear()?;
+        self.renderer.print_above(&output)
+    }
+}
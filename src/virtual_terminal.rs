@@ -0,0 +1,1109 @@
+use crate::builder::VirtualTerminalBuilder;
+use crate::callbacks::{history_len, TerminalCallbacks};
+use crate::event::TerminalEvent;
+use crate::input::{InputModes, PtyInput};
+use crate::render::{
+    format_history, format_line, walk_recent_scrollback, Cursor, Frame, Hyperlink, InlineRenderer,
+    NullRenderer, Renderer, StatusLine, Style,
+};
+use crate::scroll::{self, Outcome, ScrollMode};
+use crate::snapshot::{snapshot, SnapshotFormat};
+use clap::ValueEnum;
+use crossterm::{event::Event, terminal};
+use portable_pty::{native_pty_system, ChildKiller, ExitStatus, MasterPty, PtySize};
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::io::{ErrorKind, Read};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use vt100::{Color, MouseProtocolMode, Parser, Screen};
+
+pub struct VirtualTerminal {
+    shared: Arc<Shared>,
+    input: PtyInput,
+    resize: ResizeHandle,
+    killer: Box<dyn ChildKiller + Send + Sync>,
+    process_id: Option<u32>,
+    title: String,
+    started: Instant,
+    status_position: StatusPosition,
+    last_status: Option<StatusLine>,
+    focused: Option<bool>,
+    min_height: u16,
+    max_height: Option<u16>,
+    print_finished_lines: bool,
+    alt_screen: AltScreen,
+    /// Whether the child's alternate screen currently fills the whole real
+    /// terminal.
+    fullscreen: bool,
+    renderer: Box<dyn Renderer>,
+    scroll: Option<ScrollMode>,
+}
+
+const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusPosition {
+    /// No status line
+    Off,
+    /// Above the panel
+    Top,
+    /// Below the panel
+    Bottom,
+}
+
+/// How programs on the alternate screen, like editors and pagers, are shown.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AltScreen {
+    /// In the panel, at its full height
+    Fixed,
+    /// Over the whole terminal, returning to the panel when they exit
+    Fullscreen,
+}
+
+/// State shared between the reader and waiter threads and the renderer.
+struct Shared {
+    parser: Mutex<Parser<TerminalCallbacks>>,
+    /// Size requested for the panel, which the PTY returns to after a
+    /// full-screen program.
+    panel_size: Mutex<(u16, u16)>,
+    fullscreen: AtomicBool,
+    dirty: AtomicBool,
+    eof: AtomicBool,
+    /// Lines to print above the panel, when finished lines are printed.
+    /// Locked after the parser.
+    finished_lines: Mutex<FinishedLines>,
+    exit: Mutex<Option<(ExitStatus, Instant)>>,
+    exited: Condvar,
+    subscribers: Mutex<Vec<Sender<TerminalEvent>>>,
+}
+
+impl Shared {
+    /// Sends `event` to every subscriber, forgetting those that hung up.
+    fn emit(&self, event: TerminalEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if !subscribers.is_empty() {
+            subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+}
+
+/// Cloneable handle for resizing the child's PTY from another thread.
+#[derive(Clone)]
+pub struct ResizeHandle {
+    master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
+    shared: Arc<Shared>,
+}
+
+impl ResizeHandle {
+    /// Resizes the panel. While a program fills the whole real terminal the
+    /// new size only applies once it returns to the panel.
+    pub fn resize(&self, rows: u16, cols: u16) -> anyhow::Result<()> {
+        *self.shared.panel_size.lock().unwrap() = (rows, cols);
+        if self.shared.fullscreen.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        self.apply(rows, cols)
+    }
+
+    fn apply(&self, rows: u16, cols: u16) -> anyhow::Result<()> {
+        self.master.lock().unwrap().resize(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+        self.shared
+            .parser
+            .lock()
+            .unwrap()
+            .screen_mut()
+            .set_size(rows, cols);
+        self.shared.dirty.store(true, Ordering::Release);
+        self.shared.emit(TerminalEvent::Resize {
+            rows,
+            cols,
+            time: Instant::now(),
+        });
+        Ok(())
+    }
+}
+
+/// Drains the PTY into the parser as fast as output arrives, so the child
+/// never blocks on a full PTY buffer while the renderer is idle.
+fn pump_output(
+    mut reader: Box<dyn Read + Send>,
+    shared: Arc<Shared>,
+    input_modes: Arc<InputModes>,
+    print_finished_lines: bool,
+) {
+    let mut buf = [0; 8192];
+    let mut counter = ScrollCounter::new(print_finished_lines);
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let time = Instant::now();
+                let mut parser = shared.parser.lock().unwrap();
+                let lines = counter.process(&mut parser, &buf[..n]);
+                if print_finished_lines {
+                    shared.finished_lines.lock().unwrap().scrolled(lines);
+                }
+                input_modes.update(parser.screen());
+                shared.dirty.store(true, Ordering::Release);
+                drop(parser);
+
+                shared.emit(TerminalEvent::Output {
+                    bytes: buf[..n].to_vec(),
+                    time,
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // Linux reports EIO once the child side of the PTY is closed
+            Err(_) => break,
+        }
+    }
+
+    shared.eof.store(true, Ordering::Release);
+}
+
+/// Counts the lines the child scrolls into the scrollback of the main screen
+/// as they scroll, also once the scrollback is full and each new line drops
+/// the oldest one, and captures them if asked to.
+///
+/// vt100 moves a view that is scrolled back up along with every line that
+/// scrolls into the history, so the view is scrolled back by one line while
+/// output is processed and the lines are read off how far it moved.
+struct ScrollCounter {
+    /// The scroll position to return to and the length of the history
+    /// before counting started. Stays set while the alternate screen hides
+    /// the main one, as its history can only be read once it returns.
+    counting: Option<(usize, usize)>,
+    /// Whether to return the lines that scrolled.
+    capture: bool,
+}
+
+impl ScrollCounter {
+    fn new(capture: bool) -> Self {
+        ScrollCounter {
+            counting: None,
+            capture,
+        }
+    }
+
+    /// Processes `bytes`, adding the lines they scrolled into the history to
+    /// the count in the callbacks, and returns those lines formatted for
+    /// printing when capturing.
+    fn process(&mut self, parser: &mut Parser<TerminalCallbacks>, bytes: &[u8]) -> Vec<String> {
+        // Fed in pieces that scroll at most one line each, so every line is
+        // read before the next one can push it out of a short history: up
+        // to each escape sequence and line feed, and at most a row of text
+        // that can wrap only once. This also starts counting over right
+        // after the child returns from the alternate screen. A hyperlink
+        // sequence starts a piece, so the count is up to date when it ends.
+        let cols = parser.screen().size().1.max(1) as usize;
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for end in 1..=bytes.len() {
+            if end == bytes.len()
+                || end - start >= cols
+                || matches!(bytes[end], b'\x1b' | b'\n' | b'\x0b' | b'\x0c')
+            {
+                let scrolled = self.process_piece(parser, &bytes[start..end]);
+                parser.callbacks_mut().scrolled += scrolled;
+                if self.capture && scrolled > 0 {
+                    walk_recent_scrollback(parser.screen_mut(), scrolled, |screen, chunk| {
+                        lines.extend((0..chunk).map(|row| format_line(screen, row)));
+                    });
+                }
+                start = end;
+            }
+        }
+        lines
+    }
+
+    fn process_piece(&mut self, parser: &mut Parser<TerminalCallbacks>, bytes: &[u8]) -> usize {
+        if self.counting.is_none() && !parser.screen().alternate_screen() {
+            let screen = parser.screen_mut();
+            self.counting = Some((screen.scrollback(), history_len(screen)));
+            screen.set_scrollback(1);
+        }
+        parser.process(bytes);
+
+        let screen = parser.screen_mut();
+        if screen.alternate_screen() {
+            return 0;
+        }
+        let Some((offset, history)) = self.counting.take() else {
+            return 0;
+        };
+        let len = history_len(screen);
+        match screen.scrollback().checked_sub(1) {
+            Some(lines) => {
+                // Where vt100 would have moved the view without the counting
+                screen.set_scrollback(if offset > 0 {
+                    (offset + lines).min(len)
+                } else {
+                    0
+                });
+                lines
+            }
+            // An empty history can't be scrolled back and entering the
+            // alternate screen scrolls the view down, so then only the
+            // growth of the history counts
+            None => len.saturating_sub(history),
+        }
+    }
+}
+
+/// Lines waiting to be printed above the panel, and the rows printed while
+/// they were still on the screen, above a panel cut down by its height
+/// limits.
+#[derive(Default)]
+struct FinishedLines {
+    /// Formatted lines that weren't printed yet.
+    pending: String,
+    /// Formatted top rows of the screen as they were printed, from the top.
+    printed: VecDeque<String>,
+}
+
+impl FinishedLines {
+    /// Queues lines that scrolled off the top of the screen, except those
+    /// printed unchanged while they were still on it.
+    fn scrolled(&mut self, lines: Vec<String>) {
+        for line in lines {
+            if self.printed.pop_front().as_ref() != Some(&line) {
+                self.pending.push_str(&line);
+            }
+        }
+    }
+
+    /// Queues the first `rows` rows of the main screen, which have left the
+    /// panel, unless they were printed as they are now.
+    fn finish_rows(&mut self, screen: &mut Screen, rows: u16) {
+        let offset = screen.scrollback();
+        screen.set_scrollback(0);
+        for row in 0..rows {
+            let line = format_line(screen, row);
+            match self.printed.get_mut(row as usize) {
+                Some(printed) if *printed == line => continue,
+                Some(printed) => *printed = line.clone(),
+                None => self.printed.push_back(line.clone()),
+            }
+            self.pending.push_str(&line);
+        }
+        screen.set_scrollback(offset);
+    }
+}
+
+/// Converts an exit status into a shell-style exit code, mapping deaths by
+/// signal N to 128+N.
+pub fn exit_code(status: &ExitStatus) -> i32 {
+    match status.signal() {
+        Some(name) => 128 + signal_number(name).unwrap_or(0),
+        None => status.exit_code() as i32,
+    }
+}
+
+/// Reverses the `strsignal` description that portable-pty stores for
+/// signalled children back into a signal number.
+fn signal_number(name: &str) -> Option<i32> {
+    if let Some(number) = name.strip_prefix("Signal ") {
+        return number.parse().ok();
+    }
+
+    (1..=64).find(|&signal| {
+        let description = unsafe { libc::strsignal(signal) };
+        !description.is_null()
+            && unsafe { std::ffi::CStr::from_ptr(description) }.to_string_lossy() == name
+    })
+}
+
+impl VirtualTerminal {
+    pub fn builder(program: impl AsRef<OsStr>) -> VirtualTerminalBuilder {
+        VirtualTerminalBuilder::new(program)
+    }
+
+    pub(crate) fn spawn(options: VirtualTerminalBuilder) -> anyhow::Result<Self> {
+        let VirtualTerminalBuilder {
+            command,
+            rows,
+            cols,
+            min_height,
+            max_height,
+            scrollback,
+            print_finished_lines,
+            status_position,
+            alt_screen,
+            passthrough,
+            renderer,
+            subscribers,
+        } = options;
+        let renderer = renderer.unwrap_or_else(|| Box::new(InlineRenderer::new()));
+
+        let pty_system = native_pty_system();
+        let pair = pty_system.openpty(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+
+        let title = command.as_unix_command_line().unwrap_or_default();
+        let mut child = pair.slave.spawn_command(command)?;
+        let started = Instant::now();
+
+        let reader = pair.master.try_clone_reader()?;
+        let writer = pair.master.take_writer()?;
+        let input_modes = Arc::new(InputModes::default());
+        let shared = Arc::new(Shared {
+            // Counting scrolled lines needs room for one more line than the
+            // view is scrolled back by
+            parser: Mutex::new(Parser::new_with_callbacks(
+                rows,
+                cols,
+                if print_finished_lines {
+                    scrollback.max(2)
+                } else {
+                    scrollback
+                },
+                TerminalCallbacks::new(passthrough),
+            )),
+            panel_size: Mutex::new((rows, cols)),
+            fullscreen: AtomicBool::new(false),
+            dirty: AtomicBool::new(false),
+            eof: AtomicBool::new(false),
+            finished_lines: Mutex::new(FinishedLines::default()),
+            exit: Mutex::new(None),
+            exited: Condvar::new(),
+            subscribers: Mutex::new(subscribers),
+        });
+        let resize = ResizeHandle {
+            master: Arc::new(Mutex::new(pair.master)),
+            shared: shared.clone(),
+        };
+
+        {
+            let shared = shared.clone();
+            let input_modes = input_modes.clone();
+            std::thread::spawn(move || {
+                pump_output(reader, shared, input_modes, print_finished_lines)
+            });
+        }
+
+        let killer = child.clone_killer();
+        let process_id = child.process_id();
+        {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                let status = child
+                    .wait()
+                    .unwrap_or_else(|_| ExitStatus::with_exit_code(1));
+                let time = Instant::now();
+                *shared.exit.lock().unwrap() = Some((status.clone(), time));
+                shared.exited.notify_all();
+                shared.dirty.store(true, Ordering::Release);
+                shared.emit(TerminalEvent::Exit { status, time });
+            });
+        }
+
+        Ok(VirtualTerminal {
+            shared,
+            input: PtyInput::new(writer, input_modes),
+            resize,
+            killer,
+            process_id,
+            title,
+            started,
+            status_position,
+            last_status: None,
+            focused: None,
+            min_height,
+            max_height,
+            print_finished_lines,
+            alt_screen,
+            fullscreen: false,
+            renderer,
+            scroll: None,
+        })
+    }
+
+    /// Drops the terminal's own renderer once it is drawn as a panel of a
+    /// [`PanelStack`](crate::PanelStack), whose renderer owns the real
+    /// terminal instead.
+    pub(crate) fn disable_renderer(&mut self) {
+        self.renderer = Box::new(NullRenderer);
+    }
+
+    /// Returns a handle that resizes the child's PTY.
+    pub fn resize_handle(&self) -> ResizeHandle {
+        self.resize.clone()
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) -> anyhow::Result<()> {
+        self.resize.resize(rows, cols)
+    }
+
+    /// Routes an event from the real terminal either to scroll mode or to
+    /// the child.
+    pub fn handle_event(&mut self, event: &Event) -> anyhow::Result<()> {
+        if let Some(scroll) = &mut self.scroll {
+            let outcome =
+                scroll.handle_event(event, self.shared.parser.lock().unwrap().screen_mut());
+            self.shared.dirty.store(true, Ordering::Release);
+            if let Outcome::Exit = outcome {
+                self.exit_scroll_mode()?;
+            }
+            return Ok(());
+        }
+
+        if scroll::is_toggle_key(event) || scroll::is_enter_key(event) {
+            let mut scroll = ScrollMode::new();
+            scroll.handle_event(event, self.shared.parser.lock().unwrap().screen_mut());
+            self.scroll = Some(scroll);
+            self.shared.dirty.store(true, Ordering::Release);
+            return Ok(());
+        }
+
+        if let Event::Mouse(mouse) = event {
+            if let Some((0, row, col)) = self.renderer.locate(mouse.row, mouse.column) {
+                self.input.send_mouse(mouse, row, col)?;
+            }
+            return Ok(());
+        }
+
+        self.input.send_event(event)?;
+        Ok(())
+    }
+
+    /// Returns true while the output history is being browsed.
+    pub fn is_scrolling(&self) -> bool {
+        self.scroll.is_some()
+    }
+
+    /// Returns the panel to the live view if it is scrolled back.
+    pub fn exit_scroll_mode(&mut self) -> anyhow::Result<()> {
+        if self.scroll.take().is_some() {
+            let mut parser = self.shared.parser.lock().unwrap();
+            parser.screen_mut().set_scrollback(0);
+            self.shared.dirty.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Returns a handle that writes input into the child's PTY.
+    pub fn input(&self) -> PtyInput {
+        self.input.clone()
+    }
+
+    pub fn process_id(&self) -> Option<u32> {
+        self.process_id
+    }
+
+    /// Kills the child.
+    pub fn kill(&mut self) -> anyhow::Result<()> {
+        self.killer.kill()?;
+        Ok(())
+    }
+
+    /// Sends `signal` to the child's process group, so everything it spawned
+    /// on the PTY receives it too.
+    pub fn signal(&self, signal: i32) -> anyhow::Result<()> {
+        let Some(pid) = self.process_id else {
+            return Ok(());
+        };
+
+        // The child is a session leader, so its process group id is its pid.
+        // The group outlives the child as long as any of its members do.
+        if unsafe { libc::killpg(pid as libc::pid_t, signal) } == -1 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                return Err(error.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks until the child exits and returns its exit status.
+    pub fn wait(&self) -> ExitStatus {
+        let mut exit = self.shared.exit.lock().unwrap();
+        loop {
+            if let Some((status, _)) = &*exit {
+                return status.clone();
+            }
+            exit = self.shared.exited.wait(exit).unwrap();
+        }
+    }
+
+    /// Blocks until the child exits or `timeout` elapses, returning its exit
+    /// status if it exited.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<ExitStatus> {
+        let exit = self.shared.exit.lock().unwrap();
+        let (exit, _) = self
+            .shared
+            .exited
+            .wait_timeout_while(exit, timeout, |exit| exit.is_none())
+            .unwrap();
+        exit.as_ref().map(|(status, _)| status.clone())
+    }
+
+    /// Returns the exit status if the child has already exited.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        let exit = self.shared.exit.lock().unwrap();
+        exit.as_ref().map(|(status, _)| status.clone())
+    }
+
+    /// Returns true once the child has closed its side of the PTY and all of
+    /// its output has been parsed.
+    pub fn is_finished(&self) -> bool {
+        self.shared.eof.load(Ordering::Acquire)
+    }
+
+    /// Runs `f` with the current screen of the virtual terminal.
+    pub fn with_screen<T>(&self, f: impl FnOnce(&Screen) -> T) -> T {
+        f(self.shared.parser.lock().unwrap().screen())
+    }
+
+    /// Formats the current screen, e.g. to assert what a program shows.
+    pub fn snapshot(&self, format: SnapshotFormat) -> String {
+        snapshot(self.lock_parser().screen(), format)
+    }
+
+    pub fn get_used_height(&self) -> u16 {
+        used_height(self.shared.parser.lock().unwrap().screen())
+    }
+
+    /// Draws the current screen if it changed since the last call, returning
+    /// whether a frame was drawn.
+    pub fn render(&mut self) -> anyhow::Result<bool> {
+        self.update_fullscreen(true)?;
+        if !self.take_changed() {
+            return Ok(false);
+        }
+
+        let mut parser = self.shared.parser.lock().unwrap();
+        let (header, footer) = self.status_lines(parser.screen_mut());
+        let cursor = self.cursor(&parser);
+        let (top, rows) = self.shown_rows(parser.screen(), cursor);
+        let hyperlinks = hyperlinks(&parser, top..top + rows);
+        self.renderer
+            .passthrough(&std::mem::take(&mut parser.callbacks_mut().host_output))?;
+        if let Some(lines) = self.finished_lines(&mut parser, top) {
+            self.renderer.print_above(&lines)?;
+        }
+
+        let screen = parser.screen();
+        self.renderer.render(&[Frame {
+            screen,
+            top,
+            rows,
+            header: header.as_ref(),
+            footer: footer.as_ref(),
+            cursor,
+            fullscreen: self.fullscreen,
+            mouse: self.mouse_mode(screen),
+            hyperlinks: &hyperlinks,
+        }])?;
+
+        Ok(true)
+    }
+
+    /// With [`AltScreen::Fullscreen`], resizes the PTY to the whole real
+    /// terminal while the child is on the alternate screen and back to the
+    /// panel size afterwards. `allowed` is false for panels of a stack that
+    /// don't have the focus.
+    pub(crate) fn update_fullscreen(&mut self, allowed: bool) -> anyhow::Result<()> {
+        let fullscreen = allowed
+            && self.alt_screen == AltScreen::Fullscreen
+            && self.scroll.is_none()
+            && self.exit_status().is_none()
+            && self.lock_parser().screen().alternate_screen();
+
+        if fullscreen {
+            self.shared.fullscreen.store(true, Ordering::Release);
+            let (cols, rows) = terminal::size()?;
+            if self.lock_parser().screen().size() != (rows, cols) {
+                self.resize.apply(rows, cols)?;
+            }
+        } else if self.shared.fullscreen.swap(false, Ordering::AcqRel) {
+            let (rows, cols) = *self.shared.panel_size.lock().unwrap();
+            self.resize.apply(rows, cols)?;
+        }
+
+        if fullscreen != self.fullscreen {
+            self.fullscreen = fullscreen;
+            self.shared.dirty.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Whether the panel currently fills the whole real terminal.
+    pub(crate) fn is_fullscreen(&self) -> bool {
+        self.fullscreen
+    }
+
+    /// Returns whether the screen or status line changed since the last call.
+    pub(crate) fn take_changed(&mut self) -> bool {
+        let status = self.status_line();
+        let dirty = self.shared.dirty.swap(false, Ordering::AcqRel);
+        if !dirty && status == self.last_status {
+            return false;
+        }
+        self.last_status = status;
+        true
+    }
+
+    /// Takes the lines that scrolled off the screen or out of the panel
+    /// shown from row `top` since the last call, to print them above the
+    /// panel. Returns `None` while there are none or while the panel fills
+    /// the whole real terminal.
+    pub(crate) fn finished_lines(
+        &self,
+        parser: &mut Parser<TerminalCallbacks>,
+        top: u16,
+    ) -> Option<String> {
+        if !self.print_finished_lines || self.fullscreen {
+            return None;
+        }
+        let mut finished = self.shared.finished_lines.lock().unwrap();
+        let screen = parser.screen_mut();
+        // Rows above the panel only count on the main screen as it is live
+        if !screen.alternate_screen() && screen.scrollback() == 0 {
+            finished.finish_rows(screen, top);
+        }
+        let lines = std::mem::take(&mut finished.pending);
+        (!lines.is_empty()).then_some(lines)
+    }
+
+    pub(crate) fn lock_parser(&self) -> MutexGuard<'_, Parser<TerminalCallbacks>> {
+        self.shared.parser.lock().unwrap()
+    }
+
+    /// Returns the cursor to show in the panel: none once the child exited,
+    /// while scrolled back or while another panel has the focus.
+    pub(crate) fn cursor(&self, parser: &Parser<TerminalCallbacks>) -> Option<Cursor> {
+        if self.scroll.is_some() || self.focused == Some(false) || self.exit_status().is_some() {
+            return None;
+        }
+
+        let screen = parser.screen();
+        let (row, col) = screen.cursor_position();
+        Some(Cursor {
+            row,
+            col,
+            visible: !screen.hide_cursor(),
+            shape: parser.callbacks().cursor_shape,
+        })
+    }
+
+    /// Returns the first row and the number of rows of the screen to draw:
+    /// all of them while full screen, otherwise the rows in use within the
+    /// panel's height limits.
+    pub(crate) fn shown_rows(&self, screen: &Screen, cursor: Option<Cursor>) -> (u16, u16) {
+        if self.fullscreen {
+            return (0, screen.size().0);
+        }
+        shown_window(screen, cursor, self.min_height, self.max_height)
+    }
+
+    /// Returns the mouse events to capture on the host terminal: the wheel
+    /// in scroll mode, otherwise whatever the child asked for while it has
+    /// the focus.
+    pub(crate) fn mouse_mode(&self, screen: &Screen) -> MouseProtocolMode {
+        if self.scroll.is_some() {
+            return MouseProtocolMode::PressRelease;
+        }
+        if self.focused == Some(false) || self.exit_status().is_some() {
+            return MouseProtocolMode::None;
+        }
+        screen.mouse_protocol_mode()
+    }
+
+    /// Returns the lines drawn above and below the panel.
+    pub(crate) fn status_lines(
+        &self,
+        screen: &mut Screen,
+    ) -> (Option<StatusLine>, Option<StatusLine>) {
+        if self.fullscreen {
+            return (None, None);
+        }
+        let status = self.last_status.clone();
+        let scroll_status = self.scroll.as_ref().map(|scroll| scroll.status(screen));
+        match self.status_position {
+            StatusPosition::Top => (status, scroll_status),
+            StatusPosition::Bottom => (None, scroll_status.or(status)),
+            StatusPosition::Off => (None, scroll_status),
+        }
+    }
+
+    /// Shows a status line with the command, elapsed time and run state
+    /// above or below the panel.
+    pub fn set_status_position(&mut self, position: StatusPosition) {
+        self.status_position = position;
+    }
+
+    /// Marks the status line as focused or unfocused, or drops the marker
+    /// with `None` when the panel is not part of a stack.
+    pub(crate) fn set_focused(&mut self, focused: Option<bool>) {
+        self.focused = focused;
+    }
+
+    /// The command line shown in the status line.
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// The window title last set by the child, shown in the status line
+    /// instead of the command line.
+    pub fn window_title(&self) -> Option<String> {
+        self.lock_parser().callbacks().title.clone()
+    }
+
+    fn status_line(&self) -> Option<StatusLine> {
+        if self.status_position == StatusPosition::Off {
+            return None;
+        }
+
+        let exit = self.shared.exit.lock().unwrap().clone();
+        let elapsed = exit.as_ref().map_or_else(Instant::now, |(_, at)| *at) - self.started;
+        let (icon, right, bg) = match &exit {
+            None => {
+                let frame = (elapsed.as_millis() / 100) as usize % SPINNER.len();
+                (SPINNER[frame], format_elapsed(elapsed), Color::Idx(4))
+            }
+            Some((status, _)) if status.success() => ("✔", format_elapsed(elapsed), Color::Idx(2)),
+            Some((status, _)) => (
+                "✘",
+                format!("exit {}  {}", exit_code(status), format_elapsed(elapsed)),
+                Color::Idx(1),
+            ),
+        };
+
+        let marker = if self.focused == Some(true) {
+            "▶"
+        } else {
+            " "
+        };
+        Some(StatusLine {
+            left: format!(
+                "{}{} {}",
+                marker,
+                icon,
+                self.window_title().as_deref().unwrap_or(&self.title)
+            ),
+            right: format!("{} ", right),
+            style: Style {
+                fg: Color::Idx(15),
+                bg,
+                bold: true,
+                ..Style::default()
+            },
+        })
+    }
+
+    /// Draws the panel again on the next render, e.g. after the real
+    /// terminal was handed to another program.
+    pub fn redraw(&self) {
+        self.shared.dirty.store(true, Ordering::Release);
+    }
+
+    /// Erases the panel from the real terminal.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.renderer.clear()
+    }
+
+    /// Replaces the panel with the complete output history, so it ends up
+    /// in the real terminal's scrollback.
+    pub fn dump_history(&mut self) -> anyhow::Result<()> {
+        let history = self.history();
+        self.renderer.clear()?;
+        self.renderer.print_above(&history)
+    }
+
+    /// Formats the complete output history for printing, leaving out the
+    /// lines already printed above the panel.
+    pub(crate) fn history(&self) -> String {
+        let mut parser = self.lock_parser();
+        let rows = used_height(parser.screen());
+        if self.print_finished_lines {
+            let mut finished = self.shared.finished_lines.lock().unwrap();
+            finished.finish_rows(parser.screen_mut(), rows);
+            return std::mem::take(&mut finished.pending);
+        }
+        format_history(parser.screen_mut(), rows)
+    }
+}
+
+/// Returns the hyperlinks in `rows` of the screen.
+pub(crate) fn hyperlinks(parser: &Parser<TerminalCallbacks>, rows: Range<u16>) -> Vec<Hyperlink> {
+    parser.callbacks().hyperlinks(parser.screen(), rows)
+}
+
+pub(crate) fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Rows of `screen` worth drawing: everything up to the last non-empty row
+/// or the visible cursor, whichever is lower. The alternate screen is always
+/// shown in full, so full-screen programs keep a steady height.
+pub(crate) fn visible_rows(screen: &Screen, cursor: Option<Cursor>) -> u16 {
+    let (rows, _) = screen.size();
+    if screen.alternate_screen() {
+        return rows;
+    }
+    let cursor_rows = cursor.filter(|c| c.visible).map_or(0, |c| c.row + 1);
+    rows.min(used_height(screen).max(cursor_rows))
+}
+
+/// First row and number of rows of `screen` to draw inline: the rows in
+/// use, padded to `min_height` and cut to `max_height` by dropping rows
+/// from the top, unless that would hide the cursor.
+pub(crate) fn shown_window(
+    screen: &Screen,
+    cursor: Option<Cursor>,
+    min_height: u16,
+    max_height: Option<u16>,
+) -> (u16, u16) {
+    let (rows, _) = screen.size();
+    let used = visible_rows(screen, cursor);
+    let height = used
+        .max(min_height)
+        .min(max_height.unwrap_or(rows))
+        .min(rows);
+    let mut top = used.max(height) - height;
+    if let Some(cursor) = cursor.filter(|c| c.visible)
+        && cursor.row < top
+    {
+        top = cursor.row;
+    }
+    (top, height)
+}
+
+fn used_height(screen: &Screen) -> u16 {
+    let (rows, _) = screen.size();
+
+    for row in (0..rows).rev() {
+        if is_row_non_empty(screen, row) {
+            return (row + 1).min(rows);
+        }
+    }
+
+    0
+}
+
+fn is_row_non_empty(screen: &Screen, row: u16) -> bool {
+    let mut col = 0;
+    while let Some(cell) = screen.cell(row, col) {
+        if !cell.contents().is_empty() && cell.contents() != " " {
+            return true;
+        }
+        // A wide character covers the next cell as well, which holds
+        // nothing of its own
+        col += if cell.is_wide() { 2 } else { 1 };
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(rows: u16, cols: u16, output: &str) -> Parser {
+        let mut parser = Parser::new(rows, cols, 0);
+        parser.process(output.as_bytes());
+        parser
+    }
+
+    #[test]
+    fn wide_character_in_the_last_columns_uses_the_row() {
+        let parser = screen(4, 10, "abcdefgh字");
+        assert!(parser.screen().cell(0, 9).unwrap().is_wide_continuation());
+        assert_eq!(used_height(parser.screen()), 1);
+    }
+
+    #[test]
+    fn wide_character_at_the_last_column_wraps() {
+        let parser = screen(4, 10, "abcdefghi字");
+        assert_eq!(parser.screen().cell(1, 0).unwrap().contents(), "字");
+        assert_eq!(used_height(parser.screen()), 2);
+    }
+
+    #[test]
+    fn row_with_only_a_wide_character_is_non_empty() {
+        let parser = screen(4, 10, "\x1b[2;9H字");
+        assert!(!is_row_non_empty(parser.screen(), 0));
+        assert!(is_row_non_empty(parser.screen(), 1));
+    }
+
+    #[test]
+    fn exit_codes_follow_the_shell() {
+        assert_eq!(exit_code(&ExitStatus::with_exit_code(0)), 0);
+        assert_eq!(exit_code(&ExitStatus::with_exit_code(3)), 3);
+        assert_eq!(exit_code(&ExitStatus::with_signal("Signal 9")), 137);
+    }
+
+    #[test]
+    fn exit_codes_map_signal_descriptions_back_to_numbers() {
+        let description = unsafe { std::ffi::CStr::from_ptr(libc::strsignal(libc::SIGTERM)) };
+        let status = ExitStatus::with_signal(&description.to_string_lossy());
+        assert_eq!(exit_code(&status), 128 + libc::SIGTERM);
+        assert_eq!(exit_code(&ExitStatus::with_signal("Not a signal")), 128);
+    }
+
+    fn cursor(row: u16, visible: bool) -> Option<Cursor> {
+        let mut cursor = Cursor::new(row, 0);
+        cursor.visible = visible;
+        Some(cursor)
+    }
+
+    #[test]
+    fn visible_rows_end_at_the_last_output_or_the_cursor() {
+        assert_eq!(visible_rows(screen(6, 10, "").screen(), None), 0);
+        assert_eq!(visible_rows(screen(6, 10, "a\r\nb").screen(), None), 2);
+        let parser = screen(6, 10, "a\r\n\r\n\r\n");
+        assert_eq!(visible_rows(parser.screen(), cursor(3, true)), 4);
+        assert_eq!(visible_rows(parser.screen(), cursor(3, false)), 1);
+    }
+
+    #[test]
+    fn alternate_screen_is_shown_in_full() {
+        let parser = screen(6, 10, "\x1b[?1049hx");
+        assert_eq!(visible_rows(parser.screen(), cursor(0, true)), 6);
+    }
+
+    #[test]
+    fn shown_window_pads_to_the_minimum_height() {
+        let parser = screen(10, 10, "a\r\nb");
+        assert_eq!(shown_window(parser.screen(), None, 4, None), (0, 4));
+        assert_eq!(shown_window(parser.screen(), None, 20, None), (0, 10));
+    }
+
+    #[test]
+    fn shown_window_drops_rows_from_the_top_to_fit_the_maximum_height() {
+        let parser = screen(10, 10, "1\r\n2\r\n3\r\n4\r\n5");
+        assert_eq!(shown_window(parser.screen(), None, 0, Some(2)), (3, 2));
+        assert_eq!(
+            shown_window(parser.screen(), cursor(4, true), 0, Some(2)),
+            (3, 2)
+        );
+        // The cursor stays in view even above the last rows
+        assert_eq!(
+            shown_window(parser.screen(), cursor(1, true), 0, Some(2)),
+            (1, 2)
+        );
+        assert_eq!(
+            shown_window(parser.screen(), cursor(1, false), 0, Some(2)),
+            (3, 2)
+        );
+    }
+
+    #[test]
+    fn formats_elapsed_time() {
+        assert_eq!(format_elapsed(Duration::from_millis(999)), "00:00");
+        assert_eq!(format_elapsed(Duration::from_secs(61)), "01:01");
+        assert_eq!(format_elapsed(Duration::from_secs(3599)), "59:59");
+        assert_eq!(format_elapsed(Duration::from_secs(3600)), "1:00:00");
+        assert_eq!(
+            format_elapsed(Duration::from_secs(100 * 3600 + 61)),
+            "100:01:01"
+        );
+    }
+
+    fn counting_parser(rows: u16, cols: u16, scrollback: usize) -> Parser<TerminalCallbacks> {
+        Parser::new_with_callbacks(rows, cols, scrollback, TerminalCallbacks::new(Vec::new()))
+    }
+
+    /// Formatted lines without their line endings and erasing.
+    fn plain<'a>(lines: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+        lines
+            .into_iter()
+            .map(|line| line.trim_end().trim_end_matches("\x1b[K"))
+            .collect()
+    }
+
+    #[test]
+    fn scroll_counter_captures_every_line_with_a_short_history() {
+        let mut parser = counting_parser(3, 10, 2);
+        let mut counter = ScrollCounter::new(true);
+        let lines = counter.process(&mut parser, b"1\r\n2\r\n3\r\n4\r\n5\r\n6");
+        assert_eq!(plain(lines.iter().map(String::as_str)), ["1", "2", "3"]);
+        let lines = counter.process(&mut parser, b"\r\n7\r\n");
+        assert_eq!(plain(lines.iter().map(String::as_str)), ["4", "5"]);
+    }
+
+    #[test]
+    fn scroll_counter_captures_wrapped_text() {
+        let mut parser = counting_parser(2, 4, 2);
+        let mut counter = ScrollCounter::new(true);
+        let lines = counter.process(&mut parser, b"aaaabbbbccccdddde");
+        assert_eq!(
+            plain(lines.iter().map(String::as_str)),
+            ["aaaa", "bbbb", "cccc"]
+        );
+    }
+
+    #[test]
+    fn scroll_counter_skips_the_alternate_screen() {
+        let mut parser = counting_parser(2, 10, 100);
+        let mut counter = ScrollCounter::new(true);
+        let lines = counter.process(
+            &mut parser,
+            b"1\r\n2\r\n\x1b[?1049hx\r\ny\r\nz\x1b[?1049l\r\n3",
+        );
+        assert_eq!(plain(lines.iter().map(String::as_str)), ["1", "2"]);
+    }
+
+    #[test]
+    fn scroll_counter_keeps_a_scrolled_back_view_anchored() {
+        let mut parser = counting_parser(2, 10, 100);
+        let mut counter = ScrollCounter::new(true);
+        counter.process(&mut parser, b"1\r\n2\r\n3\r\n4");
+        parser.screen_mut().set_scrollback(1);
+        let lines = counter.process(&mut parser, b"\r\n5\r\n6");
+        assert_eq!(plain(lines.iter().map(String::as_str)), ["3", "4"]);
+        assert_eq!(parser.screen().scrollback(), 3);
+
+        parser.screen_mut().set_scrollback(0);
+        counter.process(&mut parser, b"\r\n7");
+        assert_eq!(parser.screen().scrollback(), 0);
+    }
+
+    #[test]
+    fn scroll_counter_keeps_counting_with_a_full_history() {
+        let mut parser = counting_parser(2, 10, 2);
+        let mut counter = ScrollCounter::new(false);
+        let lines = counter.process(&mut parser, b"1\r\n2\r\n3\r\n4\r\n5");
+        assert!(lines.is_empty());
+        assert_eq!(parser.callbacks().scrolled, 3);
+        counter.process(&mut parser, b"\r\n6\r\n7\n");
+        assert_eq!(parser.callbacks().scrolled, 6);
+    }
+
+    #[test]
+    fn rows_printed_above_the_panel_are_not_printed_again() {
+        let mut parser = counting_parser(4, 10, 100);
+        let mut counter = ScrollCounter::new(true);
+        let mut finished = FinishedLines::default();
+        counter.process(&mut parser, b"1\r\n2\r\n3\r\n4");
+        finished.finish_rows(parser.screen_mut(), 2);
+        assert_eq!(plain(finished.pending.lines()), ["1", "2"]);
+        finished.pending.clear();
+
+        // The first row scrolls off as printed, the second changed before
+        finished.finish_rows(parser.screen_mut(), 2);
+        assert!(finished.pending.is_empty());
+        parser.process(b"\x1b[2;1HX\x1b[4;2H");
+        finished.scrolled(counter.process(&mut parser, b"\r\n5\r\n6"));
+        assert_eq!(plain(finished.pending.lines()), ["X"]);
+        assert_eq!(finished.printed.len(), 0);
+    }
+}